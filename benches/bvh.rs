@@ -0,0 +1,124 @@
+//! Ray throughput against the number of objects in the scene, with and
+//! without the BVH. Run with `cargo bench`.
+
+use std::time::Instant;
+
+use pathtr::material::{Color, Material};
+use pathtr::math::*;
+use pathtr::scene::{Object, Scene};
+use rand::prelude::*;
+use rand_xorshift::XorShiftRng;
+
+const RAYS: usize = 200_000;
+// The linear scan gets unbearably slow beyond this.
+const MAX_LINEAR_OBJECTS: usize = 10_000;
+
+fn main() {
+    println!(
+        "{:>8} {:>12} {:>16} {:>16}",
+        "objects", "build (ms)", "bvh (rays/ms)", "linear (rays/ms)"
+    );
+    for &n in [10, 100, 1_000, 10_000, 100_000].iter() {
+        let mut rng = XorShiftRng::seed_from_u64(n as u64);
+        let mut scene = Scene::new();
+        scene.objs.push(Object {
+            shape: Box::new(Plane {
+                point: Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: -20.0,
+                },
+                normal: Vector {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+            }),
+            material: grey(),
+        });
+        // Keep the total volume of spheres roughly constant.
+        let radius = 2.0 / (n as f32).cbrt();
+        for _ in 0..n {
+            scene.objs.push(Object {
+                shape: Box::new(Sphere {
+                    center: Point {
+                        x: 20.0 * rng.gen::<f32>() - 10.0,
+                        y: 20.0 * rng.gen::<f32>() - 10.0,
+                        z: 20.0 * rng.gen::<f32>() - 10.0,
+                    },
+                    radius,
+                }),
+                material: grey(),
+            });
+        }
+
+        let start = Instant::now();
+        scene.build();
+        let build = start.elapsed().as_secs_f64() * 1000.0;
+
+        let rays: Vec<Ray> = (0..RAYS).map(|_| random_ray(&mut rng)).collect();
+
+        let start = Instant::now();
+        let mut hits = 0;
+        for ray in rays.iter() {
+            if scene.intersect(ray).is_some() {
+                hits += 1;
+            }
+        }
+        let bvh = RAYS as f64 / (start.elapsed().as_secs_f64() * 1000.0);
+
+        let linear = if n <= MAX_LINEAR_OBJECTS {
+            let start = Instant::now();
+            let mut linear_hits = 0;
+            for ray in rays.iter() {
+                if linear_scan(&scene, ray).is_some() {
+                    linear_hits += 1;
+                }
+            }
+            assert_eq!(hits, linear_hits);
+            format!(
+                "{:.0}",
+                RAYS as f64 / (start.elapsed().as_secs_f64() * 1000.0)
+            )
+        } else {
+            "-".to_string()
+        };
+        println!("{:>8} {:>12.1} {:>16.0} {:>16}", n, build, bvh, linear);
+    }
+}
+
+fn linear_scan(scene: &Scene, ray: &Ray) -> Option<Intersection> {
+    scene
+        .objs
+        .iter()
+        .filter_map(|o| o.shape.intersect(ray))
+        .min_by(|a, b| a.distance.partial_cmp(&b.distance).unwrap())
+}
+
+fn random_ray(rng: &mut XorShiftRng) -> Ray {
+    Ray {
+        origin: Point {
+            x: 0.0,
+            y: -40.0,
+            z: 0.0,
+        },
+        direction: (Vector {
+            x: rng.gen::<f32>() - 0.5,
+            y: 1.0,
+            z: rng.gen::<f32>() - 0.5,
+        })
+        .normalize(),
+    }
+}
+
+fn grey() -> Material {
+    Material::create(
+        Color {
+            red: 0.5,
+            green: 0.5,
+            blue: 0.5,
+        },
+        1.0,
+        0.0,
+    )
+}
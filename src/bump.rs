@@ -0,0 +1,171 @@
+//! Normal and bump maps, adding surface detail by perturbing the shading
+//! normal while the geometry stays as it is.
+
+use std::sync::Arc;
+
+use crate::math::*;
+use crate::texture::Texture;
+
+/// Step in texture coordinates for the finite differences of bump maps.
+const DELTA: f32 = 1e-3;
+
+#[derive(Clone)]
+pub enum Bump {
+    /// Tangent space normals encoded as colors, x along the u texture
+    /// direction, y along v and z out of the surface.
+    Normal(Arc<dyn Texture>),
+    /// Height field along the normal, from the mean of the texture's channels
+    /// times `scale`.
+    Height {
+        texture: Arc<dyn Texture>,
+        scale: f32,
+    },
+}
+
+impl Bump {
+    /// Perturbed shading normal at the hit, on the same side as the original.
+    pub fn normal(&self, hit: &Intersection) -> Vector {
+        let n = hit.shading_normal;
+        let res = match self {
+            Bump::Normal(texture) => {
+                let c = texture.value(hit);
+                let [x, y, z] = [c.red, c.green, c.blue].map(|v| 2.0 * v - 1.0);
+                let (t, b) = tangents(hit);
+                x * t + y * b + z * n
+            }
+            Bump::Height { texture, scale } => {
+                let height = |hit: &Intersection| {
+                    let c = texture.value(hit);
+                    scale * (c.red + c.green + c.blue) / 3.0
+                };
+                let h = height(hit);
+                let hu = height(&shifted(hit, hit.dpdu, [DELTA, 0.0]));
+                let hv = height(&shifted(hit, hit.dpdv, [0.0, DELTA]));
+                // Derivatives of the displaced surface, ignoring the change of
+                // the normal itself.
+                let dpdu = hit.dpdu + ((hu - h) / DELTA) * n;
+                let dpdv = hit.dpdv + ((hv - h) / DELTA) * n;
+                let bumped = cross(dpdu, dpdv);
+                if dot(bumped, n) < 0.0 {
+                    -bumped
+                } else {
+                    bumped
+                }
+            }
+        };
+        let res = res.normalize();
+        if res.x.is_finite() && res.y.is_finite() && res.z.is_finite() {
+            res
+        } else {
+            // Degenerate derivatives, at the poles of a sphere for instance.
+            n
+        }
+    }
+}
+
+/// Unit vectors along u and roughly along v, perpendicular to the shading
+/// normal.
+fn tangents(hit: &Intersection) -> (Vector, Vector) {
+    let n = hit.shading_normal;
+    let t = hit.dpdu - dot(hit.dpdu, n) * n;
+    if t.square_length() < 1e-12 {
+        let frame = Frame::from_normal(n);
+        return (frame.tangent, frame.bitangent);
+    }
+    let t = t.normalize();
+    let b = cross(n, t);
+    // Keep v pointing the same way, whichever side the normal faces.
+    if dot(b, hit.dpdv) < 0.0 {
+        (t, -b)
+    } else {
+        (t, b)
+    }
+}
+
+/// The hit moved `duv` in texture coordinates along the derivative `dp`.
+fn shifted(hit: &Intersection, dp: Vector, duv: [f32; 2]) -> Intersection {
+    let step = (duv[0] + duv[1]) * dp;
+    Intersection {
+        point: translate(hit.point, step),
+        local: hit.local + step,
+        uv: [hit.uv[0] + duv[0], hit.uv[1] + duv[1]],
+        ..*hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Color;
+    use crate::texture::Procedural;
+
+    fn floor_hit() -> Intersection {
+        let up = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let plane = Plane {
+            point: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            normal: up,
+        };
+        let ray = Ray {
+            origin: Point {
+                x: 0.3,
+                y: 0.2,
+                z: 1.0,
+            },
+            direction: -up,
+        };
+        plane.intersect(&ray).unwrap()
+    }
+
+    #[test]
+    fn flat_normal_map_keeps_normal() {
+        let flat = Bump::Normal(Arc::new(Color {
+            red: 0.5,
+            green: 0.5,
+            blue: 1.0,
+        }));
+        let hit = floor_hit();
+        let n = flat.normal(&hit);
+        assert!((n.z - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn normal_map_tilts_along_u() {
+        let tilted = Bump::Normal(Arc::new(Color {
+            red: 1.0,
+            green: 0.5,
+            blue: 1.0,
+        }));
+        let hit = floor_hit();
+        let n = tilted.normal(&hit);
+        let t = hit.dpdu.normalize();
+        assert!((dot(n, t) - 0.5_f32.sqrt()).abs() < 1e-5);
+        assert!((n.z - 0.5_f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn height_slope() {
+        // Rising by half along u, so the normal leans back by atan(1/2).
+        let ramp = Bump::Height {
+            texture: Arc::new(Procedural(|hit: &Intersection| Color {
+                red: hit.uv[0],
+                green: hit.uv[0],
+                blue: hit.uv[0],
+            })),
+            scale: 0.5,
+        };
+        let hit = floor_hit();
+        let n = ramp.normal(&hit);
+        let t = hit.dpdu.normalize();
+        let expected = -0.5 / 1.25_f32.sqrt();
+        assert!((dot(n, t) - expected).abs() < 1e-3, "{}", dot(n, t));
+        assert!(n.z > 0.0);
+    }
+}
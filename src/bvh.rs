@@ -0,0 +1,332 @@
+use crate::math::*;
+use crate::scene::Object;
+
+const BUCKETS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+// Keeps the traversal stack bounded even for degenerate input.
+const MAX_DEPTH: usize = 60;
+// Cost of visiting an interior node relative to intersecting one primitive.
+const TRAVERSAL_COST: f32 = 0.125;
+
+struct Node {
+    bounds: Aabb,
+    // Leaves: first entry in `Bvh::indices`. Interior nodes: index of the
+    // second child, the first child directly follows its parent.
+    offset: usize,
+    // Zero for interior nodes.
+    count: usize,
+    axis: usize,
+}
+
+/// Bounding volume hierarchy over the bounded objects of a scene, built using
+/// the surface area heuristic.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+}
+
+struct Primitive {
+    index: usize,
+    bounds: Aabb,
+    centroid: Point,
+}
+
+impl Bvh {
+    pub fn empty() -> Bvh {
+        Bvh {
+            nodes: Vec::new(),
+            indices: Vec::new(),
+        }
+    }
+
+    /// Builds a hierarchy over the objects in `objs` with the given indices,
+    /// all of which must have bounds.
+    pub fn build(objs: &[Object], indices: &[usize]) -> Bvh {
+        let mut prims: Vec<Primitive> = indices
+            .iter()
+            .map(|&index| {
+                let bounds = objs[index].shape.bounds().unwrap();
+                Primitive {
+                    index,
+                    bounds,
+                    centroid: bounds.centroid(),
+                }
+            })
+            .collect();
+        let mut bvh = Bvh::empty();
+        if !prims.is_empty() {
+            bvh.build_node(&mut prims, 0);
+        }
+        bvh
+    }
+
+    fn build_node(&mut self, prims: &mut [Primitive], depth: usize) {
+        let bounds = prims.iter().fold(Aabb::empty(), |b, p| b.union(p.bounds));
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            bounds,
+            offset: self.indices.len(),
+            count: prims.len(),
+            axis: 0,
+        });
+        if prims.len() <= MAX_LEAF_SIZE || depth >= MAX_DEPTH {
+            self.indices.extend(prims.iter().map(|p| p.index));
+            return;
+        }
+
+        let centroid_bounds = prims
+            .iter()
+            .fold(Aabb::empty(), |b, p| b.include(p.centroid));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.x > extent.y && extent.x > extent.z {
+            0
+        } else if extent.y > extent.z {
+            1
+        } else {
+            2
+        };
+        let min = axis_of(centroid_bounds.min, axis);
+        let width = axis_of(centroid_bounds.max, axis) - min;
+        if width <= 0.0 {
+            // All centroids coincide, there is nothing to split on.
+            self.indices.extend(prims.iter().map(|p| p.index));
+            return;
+        }
+        let bucket = |p: &Primitive| {
+            let b = (BUCKETS as f32 * (axis_of(p.centroid, axis) - min) / width) as usize;
+            b.min(BUCKETS - 1)
+        };
+
+        let mut counts = [0; BUCKETS];
+        let mut bucket_bounds = [Aabb::empty(); BUCKETS];
+        for p in prims.iter() {
+            let b = bucket(p);
+            counts[b] += 1;
+            bucket_bounds[b] = bucket_bounds[b].union(p.bounds);
+        }
+
+        // Cost of splitting after each bucket.
+        let mut best_cost = f32::INFINITY;
+        let mut best_split = 0;
+        for split in 0..BUCKETS - 1 {
+            let (mut left, mut right) = (Aabb::empty(), Aabb::empty());
+            let (mut left_count, mut right_count) = (0, 0);
+            for b in 0..=split {
+                left = left.union(bucket_bounds[b]);
+                left_count += counts[b];
+            }
+            for b in split + 1..BUCKETS {
+                right = right.union(bucket_bounds[b]);
+                right_count += counts[b];
+            }
+            let cost = TRAVERSAL_COST
+                + (left_count as f32 * left.surface_area()
+                    + right_count as f32 * right.surface_area())
+                    / bounds.surface_area();
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+        // Testing every primitive of a leaf costs one each.
+        if best_cost >= prims.len() as f32 {
+            self.indices.extend(prims.iter().map(|p| p.index));
+            return;
+        }
+
+        let mut mid = 0;
+        for i in 0..prims.len() {
+            if bucket(&prims[i]) <= best_split {
+                prims.swap(i, mid);
+                mid += 1;
+            }
+        }
+        if mid == 0 || mid == prims.len() {
+            self.indices.extend(prims.iter().map(|p| p.index));
+            return;
+        }
+
+        let (left, right) = prims.split_at_mut(mid);
+        self.build_node(left, depth + 1);
+        let second = self.nodes.len();
+        self.build_node(right, depth + 1);
+        self.nodes[node].offset = second;
+        self.nodes[node].count = 0;
+        self.nodes[node].axis = axis;
+    }
+
+    /// Finds the closest intersection nearer than `t_max`.
+    pub fn intersect<'a>(
+        &self,
+        objs: &'a [Object],
+        ray: &Ray,
+        mut t_max: f32,
+    ) -> Option<(&'a Object, Intersection)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_direction = Vector {
+            x: 1.0 / ray.direction.x,
+            y: 1.0 / ray.direction.y,
+            z: 1.0 / ray.direction.z,
+        };
+        let negative = [
+            inv_direction.x < 0.0,
+            inv_direction.y < 0.0,
+            inv_direction.z < 0.0,
+        ];
+
+        let mut closest = None;
+        let mut stack = [0; MAX_DEPTH + 1];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.bounds.intersect(ray, inv_direction, t_max).is_some() {
+                if node.count > 0 {
+                    for &index in &self.indices[node.offset..node.offset + node.count] {
+                        let obj = &objs[index];
+                        if let Some(intersection) = obj.shape.intersect(ray) {
+                            if intersection.distance < t_max {
+                                t_max = intersection.distance;
+                                closest = Some((obj, intersection));
+                            }
+                        }
+                    }
+                } else {
+                    // Visit the nearer child first so the far one is more
+                    // likely to be culled by t_max.
+                    if negative[node.axis] {
+                        stack[stack_size] = current + 1;
+                        current = node.offset;
+                    } else {
+                        stack[stack_size] = node.offset;
+                        current += 1;
+                    }
+                    stack_size += 1;
+                    continue;
+                }
+            }
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            current = stack[stack_size];
+        }
+        closest
+    }
+}
+
+fn axis_of(p: Point, axis: usize) -> f32 {
+    match axis {
+        0 => p.x,
+        1 => p.y,
+        _ => p.z,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::{Color, Material};
+    use rand::prelude::*;
+    use rand_xorshift::XorShiftRng;
+
+    fn random_spheres(n: usize, rng: &mut XorShiftRng) -> Vec<Object> {
+        (0..n)
+            .map(|_| Object {
+                shape: Box::new(Sphere {
+                    center: Point {
+                        x: 20.0 * rng.gen::<f32>() - 10.0,
+                        y: 20.0 * rng.gen::<f32>() - 10.0,
+                        z: 20.0 * rng.gen::<f32>() - 10.0,
+                    },
+                    radius: 0.5 * rng.gen::<f32>() + 0.1,
+                }),
+                material: Material::create(
+                    Color {
+                        red: 0.5,
+                        green: 0.5,
+                        blue: 0.5,
+                    },
+                    1.0,
+                    0.0,
+                ),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bvh_matches_linear_scan() {
+        let mut rng = XorShiftRng::seed_from_u64(1);
+        let objs = random_spheres(500, &mut rng);
+        let indices: Vec<usize> = (0..objs.len()).collect();
+        let bvh = Bvh::build(&objs, &indices);
+
+        for _ in 0..1000 {
+            let ray = Ray {
+                origin: Point {
+                    x: 0.0,
+                    y: -30.0,
+                    z: 0.0,
+                },
+                direction: (Vector {
+                    x: 2.0 * rng.gen::<f32>() - 1.0,
+                    y: 3.0,
+                    z: 2.0 * rng.gen::<f32>() - 1.0,
+                })
+                .normalize(),
+            };
+            let expected = objs
+                .iter()
+                .filter_map(|o| o.shape.intersect(&ray))
+                .map(|i| i.distance)
+                .fold(f32::INFINITY, f32::min);
+            let res = bvh.intersect(&objs, &ray, f32::INFINITY);
+            match res {
+                Some((_, intersection)) => assert_eq!(expected, intersection.distance),
+                None => assert_eq!(f32::INFINITY, expected),
+            }
+        }
+    }
+
+    #[test]
+    fn overlapping_objects_share_a_leaf() {
+        let mut rng = XorShiftRng::seed_from_u64(1);
+        let mut objs = random_spheres(8, &mut rng);
+        for (i, o) in objs.iter_mut().enumerate() {
+            o.shape = Box::new(Sphere {
+                center: Point {
+                    x: 0.1 * i as f32,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                radius: 10.0,
+            });
+        }
+        let indices: Vec<usize> = (0..objs.len()).collect();
+        let bvh = Bvh::build(&objs, &indices);
+        assert_eq!(1, bvh.nodes.len());
+
+        let spread = random_spheres(8, &mut rng);
+        assert!(Bvh::build(&spread, &indices).nodes.len() > 1);
+    }
+
+    #[test]
+    fn empty_bvh() {
+        let bvh = Bvh::build(&[], &[]);
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        assert!(bvh.intersect(&[], &ray, f32::INFINITY).is_none());
+    }
+}
@@ -0,0 +1,357 @@
+//! Renders saved part way through, to be carried on with later.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+
+use crate::film::{self, Film};
+use crate::render::{Depth, Settings};
+use crate::sampler::SamplerType;
+
+const MAGIC: &str = "pathtr checkpoint 1";
+
+/// How far a render has got: the samples so far and how many of its jobs
+/// are behind them. Jobs are numbered and seeded the same way every time, so
+/// rendering with the same seed from the next job on picks up where it was
+/// left.
+pub struct Checkpoint {
+    pub seed: u64,
+    pub samples_per_pixel: u32,
+    // What the samples were taken with, those taken otherwise wouldn't mix.
+    scene: SceneId,
+    sampler: SamplerType,
+    spectral: bool,
+    depth: Depth,
+    pub jobs_done: usize,
+    pub film: Film,
+}
+
+/// Which scene file a render is of, by its path and a hash of its contents.
+/// Meshes and textures it refers to aren't hashed, the path at least keeps
+/// scenes next to different ones apart.
+#[derive(Clone, PartialEq, Debug)]
+pub struct SceneId {
+    pub path: String,
+    pub hash: u64,
+}
+
+impl SceneId {
+    pub fn of(path: &Path) -> io::Result<SceneId> {
+        Ok(SceneId {
+            path: path.to_string_lossy().into_owned(),
+            hash: fnv1a(&fs::read(path)?),
+        })
+    }
+}
+
+/// 64-bit FNV-1a, small and plenty for telling files apart.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+/// Where and how often to save checkpoints while rendering.
+pub struct Autosave {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+impl Checkpoint {
+    pub fn new(settings: &Settings, scene: SceneId) -> Checkpoint {
+        Checkpoint {
+            seed: settings.seed,
+            samples_per_pixel: settings.samples_per_pixel,
+            scene,
+            sampler: settings.sampler,
+            spectral: settings.spectral,
+            depth: settings.depth,
+            jobs_done: 0,
+            film: Film::new(settings.width, settings.height),
+        }
+    }
+
+    /// How the render differs from what `settings` would give of `scene`, if
+    /// it does.
+    pub fn mismatch(&self, settings: &Settings, scene: &SceneId) -> Option<String> {
+        let film = &self.film;
+        if self.scene.path != scene.path {
+            Some(format!("the render is of {}", self.scene.path))
+        } else if self.scene.hash != scene.hash {
+            Some(format!("{} has changed since", self.scene.path))
+        } else if (film.width, film.height) != (settings.width, settings.height) {
+            Some(format!("the render is {}x{}", film.width, film.height))
+        } else if self.seed != settings.seed {
+            Some(format!("the render has seed {}", self.seed))
+        } else if self.samples_per_pixel != settings.samples_per_pixel {
+            Some(format!(
+                "the render has {} samples per pixel",
+                self.samples_per_pixel
+            ))
+        } else if self.sampler != settings.sampler {
+            Some(format!(
+                "the render uses the {} sampler",
+                sampler_name(self.sampler)
+            ))
+        } else if self.spectral != settings.spectral {
+            Some(if self.spectral {
+                "the render is spectral".to_string()
+            } else {
+                "the render isn't spectral".to_string()
+            })
+        } else if (self.depth.min, self.depth.max) != (settings.depth.min, settings.depth.max) {
+            Some(format!(
+                "the render has depths from {} to {}",
+                self.depth.min, self.depth.max
+            ))
+        } else {
+            None
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Checkpoint> {
+        read(&mut BufReader::new(File::open(path)?))
+    }
+
+    /// Writes to a file next to `path` first and then moves it over, so being
+    /// killed halfway through doesn't cost the previous checkpoint.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let mut w = BufWriter::new(File::create(&partial)?);
+        self.write(&mut w)?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&partial, path)
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        // A text header like PFM, then the raw film. The scene's path goes
+        // last on its line, spaces and all.
+        write!(
+            w,
+            "{}\n{} {}\n{} {}\n{} {} {} {} {}\n{:016x} {}\n",
+            MAGIC,
+            self.seed,
+            self.jobs_done,
+            self.film.width,
+            self.film.height,
+            self.samples_per_pixel,
+            sampler_name(self.sampler),
+            self.spectral as u8,
+            self.depth.min,
+            self.depth.max,
+            self.scene.hash,
+            self.scene.path
+        )?;
+        self.film.write_raw(w)?;
+        w.flush()
+    }
+}
+
+fn read<R: BufRead>(r: &mut R) -> io::Result<Checkpoint> {
+    let mut magic = String::new();
+    r.read_line(&mut magic)?;
+    if magic.trim_end() != MAGIC {
+        return Err(not_a_checkpoint());
+    }
+    let (seed, jobs_done) = match &read_words(r)?[..] {
+        [seed, jobs_done] => (parse(seed)?, parse(jobs_done)?),
+        _ => return Err(not_a_checkpoint()),
+    };
+    let (width, height): (usize, usize) = match &read_words(r)?[..] {
+        [width, height] => (parse(width)?, parse(height)?),
+        _ => return Err(not_a_checkpoint()),
+    };
+    let (samples_per_pixel, sampler, spectral, depth) = match &read_words(r)?[..] {
+        [samples_per_pixel, sampler, spectral, min, max] => (
+            parse(samples_per_pixel)?,
+            SamplerType::from_str(sampler, false).map_err(|_| not_a_checkpoint())?,
+            parse::<u8>(spectral)? != 0,
+            Depth {
+                min: parse(min)?,
+                max: parse(max)?,
+            },
+        ),
+        _ => return Err(not_a_checkpoint()),
+    };
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let scene = match line.trim_end_matches('\n').split_once(' ') {
+        Some((hash, path)) => SceneId {
+            path: path.to_string(),
+            hash: u64::from_str_radix(hash, 16).map_err(|_| not_a_checkpoint())?,
+        },
+        None => return Err(not_a_checkpoint()),
+    };
+    // Going by the file rather than the header before making room for the
+    // film, in case the header is broken.
+    let mut data = Vec::new();
+    r.read_to_end(&mut data)?;
+    let size = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(film::RAW_PIXEL_BYTES));
+    if size != Some(data.len()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "checkpoint size doesn't match its header",
+        ));
+    }
+    Ok(Checkpoint {
+        seed,
+        samples_per_pixel,
+        scene,
+        sampler,
+        spectral,
+        depth,
+        jobs_done,
+        film: Film::read_raw(&mut &data[..], width, height)?,
+    })
+}
+
+fn read_words<R: BufRead>(r: &mut R) -> io::Result<Vec<String>> {
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    Ok(line.split_whitespace().map(str::to_string).collect())
+}
+
+fn parse<T: FromStr>(word: &str) -> io::Result<T> {
+    word.parse().map_err(|_| not_a_checkpoint())
+}
+
+fn sampler_name(sampler: SamplerType) -> String {
+    sampler
+        .to_possible_value()
+        .map_or_else(String::new, |v| v.get_name().to_string())
+}
+
+fn not_a_checkpoint() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "not a pathtr checkpoint")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::{ToneMap, ToneMapping};
+    use crate::material::Color;
+
+    fn settings() -> Settings {
+        Settings {
+            width: 3,
+            height: 2,
+            samples_per_pixel: 100,
+            threads: 1,
+            seed: 42,
+            spectral: false,
+            depth: Depth::default(),
+            sampler: SamplerType::Stratified,
+            noise_threshold: None,
+            time_limit: None,
+            target_error: None,
+            tone_mapping: ToneMapping {
+                operator: ToneMap::Linear,
+                exposure: 0.0,
+            },
+        }
+    }
+
+    fn scene() -> SceneId {
+        SceneId {
+            path: "scenes/my scene.toml".to_string(),
+            hash: fnv1a(b"[camera]"),
+        }
+    }
+
+    #[test]
+    fn fnv1a_test() {
+        assert_eq!(0xcbf29ce484222325, fnv1a(b""));
+        assert_eq!(0xaf63dc4c8601ec8c, fnv1a(b"a"));
+        assert_eq!(0x85944171f73967e8, fnv1a(b"foobar"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut checkpoint = Checkpoint::new(&settings(), scene());
+        checkpoint.jobs_done = 17;
+        for (x, v) in [(0, 0.5), (0, 1.5), (2, 3.0)] {
+            checkpoint.film.add_sample(
+                x,
+                1,
+                Color {
+                    red: v,
+                    green: 2.0 * v,
+                    blue: 0.1,
+                },
+            );
+        }
+        let mut data = Vec::new();
+        checkpoint.write(&mut data).unwrap();
+
+        let loaded = read(&mut &data[..]).unwrap();
+        assert_eq!(42, loaded.seed);
+        assert_eq!(100, loaded.samples_per_pixel);
+        assert!(loaded.mismatch(&settings(), &scene()).is_none());
+        assert_eq!(17, loaded.jobs_done);
+        assert_eq!((3, 2), (loaded.film.width, loaded.film.height));
+        assert_eq!(2, loaded.film.samples(0, 1));
+        assert_eq!(0, loaded.film.samples(1, 1));
+        assert_eq!(2.0, loaded.film.pixel(0, 1).green);
+        assert_eq!(
+            checkpoint.film.error(0, 1).to_bits(),
+            loaded.film.error(0, 1).to_bits()
+        );
+
+        assert!(read(&mut &data[..data.len() - 1]).is_err());
+        assert!(read(&mut &b"PF\n3 2\n-1.0\n"[..]).is_err());
+        // Not a size to try making room for.
+        let huge =
+            b"pathtr checkpoint 1\n42 0\n4000000000 4000000000\n100 sobol 0 3 100\n0 a.toml\n";
+        assert!(read(&mut &huge[..]).is_err());
+    }
+
+    #[test]
+    fn settings_must_match() {
+        let checkpoint = Checkpoint::new(&settings(), scene());
+        let mut other = settings();
+        other.threads = 8;
+        other.noise_threshold = Some(0.1);
+        assert!(checkpoint.mismatch(&other, &scene()).is_none());
+
+        let mut moved = scene();
+        moved.path = "other.toml".to_string();
+        assert_eq!(
+            Some("the render is of scenes/my scene.toml".to_string()),
+            checkpoint.mismatch(&other, &moved)
+        );
+        let mut edited = scene();
+        edited.hash = fnv1a(b"[camera]\n");
+        assert_eq!(
+            Some("scenes/my scene.toml has changed since".to_string()),
+            checkpoint.mismatch(&other, &edited)
+        );
+
+        other.samples_per_pixel = 200;
+        assert_eq!(
+            Some("the render has 100 samples per pixel".to_string()),
+            checkpoint.mismatch(&other, &scene())
+        );
+        let mut other = settings();
+        other.sampler = SamplerType::Sobol;
+        assert_eq!(
+            Some("the render uses the stratified sampler".to_string()),
+            checkpoint.mismatch(&other, &scene())
+        );
+        let mut other = settings();
+        other.spectral = true;
+        assert!(checkpoint.mismatch(&other, &scene()).is_some());
+        let mut other = settings();
+        other.depth.max = 5;
+        assert!(checkpoint.mismatch(&other, &scene()).is_some());
+    }
+}
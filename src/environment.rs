@@ -0,0 +1,284 @@
+//! What rays leaving the scene see: a constant color, an equirectangular
+//! image surrounding everything or an analytic sky, the latter two with
+//! directions direct light sampling can pick.
+
+use std::f32::consts::{PI, TAU};
+
+use crate::material::Color;
+use crate::math::*;
+use crate::sky::Sky;
+use crate::texture::ImageTexture;
+
+pub enum Environment {
+    Constant(Color),
+    Map(EnvironmentMap),
+    Sky(Sky),
+}
+
+impl Environment {
+    pub fn radiance(&self, direction: Vector) -> Color {
+        match self {
+            Environment::Constant(color) => *color,
+            Environment::Map(map) => map.radiance(direction),
+            Environment::Sky(sky) => sky.radiance(direction),
+        }
+    }
+
+    /// Whether direct light sampling picks the environment. A constant one
+    /// is left to the BSDF samples, which already match it well.
+    pub fn is_sampled(&self) -> bool {
+        !matches!(self, Environment::Constant(_))
+    }
+
+    /// Direction towards the environment and its solid angle density.
+    pub fn sample(&self, u1: f32, u2: f32) -> Option<(Vector, f32)> {
+        match self {
+            Environment::Constant(_) => None,
+            Environment::Map(map) => map.sample(u1, u2),
+            Environment::Sky(sky) => sky.sample(u1, u2),
+        }
+    }
+
+    /// Density with which `sample` produces `direction`.
+    pub fn pdf(&self, direction: Vector) -> f32 {
+        match self {
+            Environment::Constant(_) => 0.0,
+            Environment::Map(map) => map.pdf(direction),
+            Environment::Sky(sky) => sky.pdf(direction),
+        }
+    }
+}
+
+/// Latitude-longitude image with the z axis up, its middle column facing +x
+/// before rotation.
+pub struct EnvironmentMap {
+    image: ImageTexture,
+    intensity: f32,
+    // Turn around the z axis, in radians.
+    rotation: f32,
+    // Pixels are picked proportionally to their luminance times the solid
+    // angle they cover, first the row then the column within it.
+    rows: Distribution,
+    columns: Vec<Distribution>,
+}
+
+impl EnvironmentMap {
+    pub fn new(image: ImageTexture, intensity: f32, rotation: f32) -> EnvironmentMap {
+        let (width, height) = (image.width(), image.height());
+        let columns: Vec<Distribution> = (0..height)
+            .map(|y| {
+                let sin_theta = (PI * (y as f32 + 0.5) / height as f32).sin();
+                let weights: Vec<f32> = (0..width)
+                    .map(|x| image.pixel(x, y).luminance() * sin_theta)
+                    .collect();
+                Distribution::new(&weights)
+            })
+            .collect();
+        let rows = Distribution::new(&columns.iter().map(|c| c.total).collect::<Vec<f32>>());
+        EnvironmentMap {
+            image,
+            intensity,
+            rotation,
+            rows,
+            columns,
+        }
+    }
+
+    /// Pixel seen in a direction, along with the sine of its polar angle.
+    fn pixel(&self, direction: Vector) -> (usize, usize, f32) {
+        let d = rotate(direction, -self.rotation);
+        let u = 0.5 + d.y.atan2(d.x) / TAU;
+        let theta = d.z.clamp(-1.0, 1.0).acos();
+        let x = ((u * self.image.width() as f32) as usize).min(self.image.width() - 1);
+        let y = ((theta / PI * self.image.height() as f32) as usize).min(self.image.height() - 1);
+        (x, y, theta.sin())
+    }
+
+    fn radiance(&self, direction: Vector) -> Color {
+        let (x, y, _) = self.pixel(direction);
+        self.image.pixel(x, y) * self.intensity
+    }
+
+    fn sample(&self, u1: f32, u2: f32) -> Option<(Vector, f32)> {
+        // What's left of each random number after picking a bin places the
+        // direction within the pixel.
+        let (y, fv) = self.rows.sample(u2);
+        let (x, fu) = self.columns[y].sample(u1);
+        let u = (x as f32 + fu) / self.image.width() as f32;
+        let v = (y as f32 + fv) / self.image.height() as f32;
+        let (phi, theta) = (TAU * (u - 0.5), PI * v);
+        let direction = Vector {
+            x: theta.sin() * phi.cos(),
+            y: theta.sin() * phi.sin(),
+            z: theta.cos(),
+        };
+        let pdf = self.pixel_pdf(x, y, theta.sin());
+        if pdf > 0.0 {
+            Some((rotate(direction, self.rotation), pdf))
+        } else {
+            None
+        }
+    }
+
+    fn pdf(&self, direction: Vector) -> f32 {
+        let (x, y, sin_theta) = self.pixel(direction);
+        self.pixel_pdf(x, y, sin_theta)
+    }
+
+    /// Solid angle density of directions sampled within a pixel: uniform
+    /// over the image it is the pixel's probability times the pixel count,
+    /// and the image covers 2 pi^2 sin(theta) as much solid angle.
+    fn pixel_pdf(&self, x: usize, y: usize, sin_theta: f32) -> f32 {
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let p = self.rows.probability(y) * self.columns[y].probability(x);
+        let pixels = (self.image.width() * self.image.height()) as f32;
+        p * pixels / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+/// Piecewise constant distribution over bins.
+struct Distribution {
+    // Normalized cumulative weights, starting at zero.
+    cdf: Vec<f32>,
+    total: f32,
+}
+
+impl Distribution {
+    fn new(weights: &[f32]) -> Distribution {
+        let total: f32 = weights.iter().sum();
+        // Uniform if there's nothing to go by.
+        let uniform = total <= 0.0;
+        let mut cdf = Vec::with_capacity(weights.len() + 1);
+        let mut sum = 0.0;
+        cdf.push(0.0);
+        for w in weights.iter() {
+            sum += if uniform { 1.0 } else { *w };
+            cdf.push(sum);
+        }
+        for c in cdf.iter_mut() {
+            *c /= sum;
+        }
+        // Rounding could leave the end short of one.
+        *cdf.last_mut().unwrap() = 1.0;
+        Distribution { cdf, total }
+    }
+
+    /// Bin for a uniform random number in [0, 1), along with where in the
+    /// bin it fell, which is uniform again.
+    fn sample(&self, u: f32) -> (usize, f32) {
+        let bins = self.cdf.len() - 1;
+        let i = (self.cdf.partition_point(|&c| c <= u) - 1).min(bins - 1);
+        let (lo, hi) = (self.cdf[i], self.cdf[i + 1]);
+        let offset = if hi > lo { (u - lo) / (hi - lo) } else { 0.5 };
+        (i, offset.clamp(0.0, 1.0 - f32::EPSILON))
+    }
+
+    fn probability(&self, i: usize) -> f32 {
+        self.cdf[i + 1] - self.cdf[i]
+    }
+}
+
+fn rotate(v: Vector, angle: f32) -> Vector {
+    let (sin, cos) = angle.sin_cos();
+    Vector {
+        x: cos * v.x - sin * v.y,
+        y: sin * v.x + cos * v.y,
+        z: v.z,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Wrap;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    /// Dim map with a bright spot.
+    fn spot_map(rotation: f32) -> EnvironmentMap {
+        let (width, height) = (16, 8);
+        let mut pixels = vec![
+            Color {
+                red: 0.1,
+                green: 0.1,
+                blue: 0.1,
+            };
+            width * height
+        ];
+        pixels[2 * width + 11] = Color {
+            red: 50.0,
+            green: 40.0,
+            blue: 30.0,
+        };
+        let image = ImageTexture::new(width, height, pixels, Wrap::Clamp);
+        EnvironmentMap::new(image, 1.0, rotation)
+    }
+
+    #[test]
+    fn distribution() {
+        let d = Distribution::new(&[1.0, 0.0, 3.0]);
+        assert_eq!(0.25, d.probability(0));
+        assert_eq!(0.0, d.probability(1));
+        assert_eq!((0, 0.5), d.sample(0.125));
+        assert_eq!(2, d.sample(0.25).0);
+        assert_eq!(2, d.sample(0.999).0);
+        let uniform = Distribution::new(&[0.0, 0.0]);
+        assert_eq!(0.5, uniform.probability(1));
+    }
+
+    #[test]
+    fn importance_sampling_is_unbiased() {
+        let map = spot_map(1.0);
+        let mut rng = XorShiftRng::seed_from_u64(6);
+        let n = 200000;
+        // Integral of the luminance over the sphere, once with uniformly
+        // distributed directions and once importance sampled.
+        let mut uniform = 0.0;
+        let mut sampled = 0.0;
+        let mut mismatched = 0;
+        for _ in 0..n {
+            let z: f32 = 2.0 * rng.gen::<f32>() - 1.0;
+            let phi = TAU * rng.gen::<f32>();
+            let r = (1.0 - z * z).sqrt();
+            let d = Vector {
+                x: r * phi.cos(),
+                y: r * phi.sin(),
+                z,
+            };
+            uniform += (map.radiance(d).luminance() * 4.0 * PI) as f64;
+
+            let (d, pdf) = map.sample(rng.gen(), rng.gen()).unwrap();
+            assert!((d.length() - 1.0).abs() < 1e-4);
+            // Rounding can put directions on a pixel edge in the neighbor.
+            if (map.pdf(d) - pdf).abs() > 1e-3 * pdf {
+                mismatched += 1;
+            }
+            sampled += (map.radiance(d).luminance() / pdf) as f64;
+        }
+        assert!(mismatched < n / 1000, "{}", mismatched);
+        let (uniform, sampled) = (uniform / n as f64, sampled / n as f64);
+        assert!(
+            (uniform - sampled).abs() < 0.03 * uniform,
+            "{} {}",
+            uniform,
+            sampled
+        );
+    }
+
+    #[test]
+    fn rotation_turns_the_map() {
+        let map = spot_map(0.0);
+        let turned = spot_map(0.5 * PI);
+        let d = Vector {
+            x: 0.3,
+            y: -0.5,
+            z: 0.8,
+        }
+        .normalize();
+        let a = map.radiance(d);
+        let b = turned.radiance(rotate(d, 0.5 * PI));
+        assert_eq!(a.red, b.red);
+    }
+}
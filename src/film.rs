@@ -0,0 +1,362 @@
+use std::io::{self, Read, Write};
+
+use crate::material::Color;
+
+#[derive(Copy, Clone, clap::ValueEnum)]
+pub enum ToneMap {
+    /// Clamp to the displayable range
+    Linear,
+    /// x / (1 + x) per channel
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+}
+
+/// How linear radiance is turned into displayable 8-bit sRGB.
+#[derive(Copy, Clone)]
+pub struct ToneMapping {
+    pub operator: ToneMap,
+    // In stops, the radiance is scaled by 2^exposure before tone mapping.
+    pub exposure: f32,
+}
+
+impl ToneMapping {
+    pub fn apply(&self, value: Color) -> Color {
+        let scale = self.exposure.exp2();
+        let map = |v: f32| {
+            let v = (v * scale).max(0.0);
+            let mapped = match self.operator {
+                ToneMap::Linear => v,
+                ToneMap::Reinhard => v / (1.0 + v),
+                ToneMap::Aces => (v * (2.51 * v + 0.03)) / (v * (2.43 * v + 0.59) + 0.14),
+            };
+            mapped.clamp(0.0, 1.0)
+        };
+        Color {
+            red: map(value.red),
+            green: map(value.green),
+            blue: map(value.blue),
+        }
+    }
+}
+
+/// Rectangular part of the image, rendered as one unit of work.
+#[derive(Copy, Clone)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Splits the image into tiles of at most `size` by `size` pixels, in
+/// scanline order.
+pub fn tiles(width: usize, height: usize, size: usize) -> Vec<Tile> {
+    let mut res = Vec::new();
+    for y in (0..height).step_by(size) {
+        for x in (0..width).step_by(size) {
+            res.push(Tile {
+                x,
+                y,
+                width: size.min(width - x),
+                height: size.min(height - y),
+            });
+        }
+    }
+    res
+}
+
+/// Bytes `Film::write_raw` writes per pixel.
+pub const RAW_PIXEL_BYTES: usize = 20;
+
+/// Accumulates linear radiance samples per pixel.
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    sums: Vec<Color>,
+    // Sums of the squared luminance of the samples, for their variance.
+    squares: Vec<f32>,
+    samples: Vec<u32>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize) -> Film {
+        Film {
+            width,
+            height,
+            sums: vec![
+                Color {
+                    red: 0.0,
+                    green: 0.0,
+                    blue: 0.0
+                };
+                width * height
+            ],
+            squares: vec![0.0; width * height],
+            samples: vec![0; width * height],
+        }
+    }
+
+    pub fn add_sample(&mut self, x: usize, y: usize, value: Color) {
+        let i = self.width * y + x;
+        self.sums[i] += value;
+        self.squares[i] += value.luminance().powi(2);
+        self.samples[i] += 1;
+    }
+
+    /// Adds the samples of a film covering just `tile`.
+    pub fn add_tile(&mut self, tile: &Tile, other: &Film) {
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                let i = self.width * (tile.y + y) + tile.x + x;
+                let j = other.width * y + x;
+                self.sums[i] += other.sums[j];
+                self.squares[i] += other.squares[j];
+                self.samples[i] += other.samples[j];
+            }
+        }
+    }
+
+    /// Mean radiance of a pixel.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let i = self.width * y + x;
+        if self.samples[i] == 0 {
+            return self.sums[i];
+        }
+        self.sums[i] * (1.0 / self.samples[i] as f32)
+    }
+
+    pub fn samples(&self, x: usize, y: usize) -> u32 {
+        self.samples[self.width * y + x]
+    }
+
+    pub fn total_samples(&self) -> u64 {
+        self.samples.iter().map(|&s| s as u64).sum()
+    }
+
+    /// Estimated standard error of a pixel's mean luminance, relative to
+    /// the square root of that mean. Noise grows with the square root of
+    /// the brightness, so this is about how visible it is anywhere in the
+    /// image. Infinite until there are samples to go by.
+    pub fn error(&self, x: usize, y: usize) -> f32 {
+        match self.standard_error(x, y) {
+            Some((error, mean)) => error / mean.max(1e-4).sqrt(),
+            None => f32::INFINITY,
+        }
+    }
+
+    /// Estimated standard error of a pixel's mean luminance relative to that
+    /// mean, with very dark pixels counted as slightly brighter than they
+    /// are. Infinite until there are samples to go by.
+    pub fn relative_error(&self, x: usize, y: usize) -> f32 {
+        match self.standard_error(x, y) {
+            Some((error, mean)) => error / mean.max(1e-3),
+            None => f32::INFINITY,
+        }
+    }
+
+    /// Mean of `relative_error` over all pixels, for how far the whole image
+    /// is from converged.
+    pub fn mean_relative_error(&self) -> f32 {
+        let mut sum = 0.0;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                sum += self.relative_error(x, y);
+            }
+        }
+        sum / (self.width * self.height) as f32
+    }
+
+    /// Standard error and mean of a pixel's luminance, if there are enough
+    /// samples and none of them were infinite or NaN.
+    fn standard_error(&self, x: usize, y: usize) -> Option<(f32, f32)> {
+        let i = self.width * y + x;
+        let n = self.samples[i] as f32;
+        let mean = self.sums[i].luminance() / n;
+        if n < 2.0 || !mean.is_finite() || !self.squares[i].is_finite() {
+            return None;
+        }
+        let variance = ((self.squares[i] / n - mean * mean) * n / (n - 1.0)).max(0.0);
+        Some(((variance / n).sqrt(), mean))
+    }
+
+    /// Writes the sums and sample counts of every pixel, little endian, for
+    /// `read_raw` to pick up again.
+    pub fn write_raw<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for i in 0..self.sums.len() {
+            let sum = self.sums[i];
+            for v in [sum.red, sum.green, sum.blue, self.squares[i]] {
+                w.write_all(&v.to_le_bytes())?;
+            }
+            w.write_all(&self.samples[i].to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read_raw<R: Read>(r: &mut R, width: usize, height: usize) -> io::Result<Film> {
+        let mut film = Film::new(width, height);
+        let mut word = || -> io::Result<[u8; 4]> {
+            let mut bytes = [0; 4];
+            r.read_exact(&mut bytes)?;
+            Ok(bytes)
+        };
+        for i in 0..width * height {
+            film.sums[i] = Color {
+                red: f32::from_le_bytes(word()?),
+                green: f32::from_le_bytes(word()?),
+                blue: f32::from_le_bytes(word()?),
+            };
+            film.squares[i] = f32::from_le_bytes(word()?);
+            film.samples[i] = u32::from_le_bytes(word()?);
+        }
+        Ok(film)
+    }
+
+    pub fn to_rgba8(&self, tone_mapping: &ToneMapping) -> Vec<u8> {
+        let mut img_buffer = Vec::with_capacity(self.width * self.height * 4);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let val = tone_mapping.apply(self.pixel(x, y));
+                img_buffer.push(encode_srgb(val.red));
+                img_buffer.push(encode_srgb(val.green));
+                img_buffer.push(encode_srgb(val.blue));
+                img_buffer.push(255);
+            }
+        }
+        img_buffer
+    }
+}
+
+/// sRGB transfer function of a value in [0, 1], quantized to 8 bits.
+fn encode_srgb(v: f32) -> u8 {
+    let encoded = if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (255.0 * encoded + 0.5) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(v: f32) -> Color {
+        Color {
+            red: v,
+            green: v,
+            blue: v,
+        }
+    }
+
+    #[test]
+    fn encode_srgb_test() {
+        assert_eq!(0, encode_srgb(0.0));
+        assert_eq!(255, encode_srgb(1.0));
+        // Linear 0.214 is about half way in sRGB.
+        assert_eq!(128, encode_srgb(0.2158));
+        assert_eq!(3, encode_srgb(0.001));
+    }
+
+    #[test]
+    fn tiles_cover_image() {
+        let tiles = tiles(70, 40, 32);
+        assert_eq!(6, tiles.len());
+        let mut film = Film::new(70, 40);
+        for tile in tiles.iter() {
+            let mut part = Film::new(tile.width, tile.height);
+            for y in 0..tile.height {
+                for x in 0..tile.width {
+                    part.add_sample(x, y, gray(1.0));
+                }
+            }
+            film.add_tile(tile, &part);
+        }
+        assert!(film.samples.iter().all(|&s| s == 1));
+        assert_eq!(6, tiles[5].width);
+        assert_eq!(8, tiles[5].height);
+    }
+
+    #[test]
+    fn tone_mapping_exposure() {
+        let tone_mapping = ToneMapping {
+            operator: ToneMap::Linear,
+            exposure: 1.0,
+        };
+        assert_eq!(0.5, tone_mapping.apply(gray(0.25)).red);
+        assert_eq!(1.0, tone_mapping.apply(gray(4.0)).red);
+    }
+
+    #[test]
+    fn tone_mapping_compresses_highlights() {
+        for &operator in [ToneMap::Reinhard, ToneMap::Aces].iter() {
+            let tone_mapping = ToneMapping {
+                operator,
+                exposure: 0.0,
+            };
+            let low = tone_mapping.apply(gray(1.0)).red;
+            let high = tone_mapping.apply(gray(100.0)).red;
+            assert!(low < high);
+            assert!(high <= 1.0);
+        }
+    }
+
+    #[test]
+    fn film_averages_samples() {
+        let mut film = Film::new(2, 1);
+        film.add_sample(0, 0, gray(1.0));
+        film.add_sample(0, 0, gray(0.0));
+        film.add_sample(1, 0, gray(0.25));
+        film.add_sample(1, 0, gray(0.75));
+        assert_eq!(0.5, film.pixel(0, 0).red);
+        assert_eq!(0.5, film.pixel(1, 0).green);
+    }
+
+    #[test]
+    fn error_shrinks_with_samples() {
+        let mut film = Film::new(2, 1);
+        assert_eq!(f32::INFINITY, film.error(0, 0));
+        for i in 0..100 {
+            // Alternating between 0 and 2 in one pixel, always 1 in the other.
+            film.add_sample(0, 0, gray(2.0 * (i % 2) as f32));
+            film.add_sample(1, 0, gray(1.0));
+        }
+        let error = film.error(0, 0);
+        // Standard deviation of about one over the square root of 100.
+        assert!((error - 0.1005).abs() < 1e-3, "{}", error);
+        assert!(film.error(1, 0) < 1e-3);
+        // Relative to a mean of one in both pixels.
+        let relative = film.relative_error(0, 0);
+        assert!((relative - error).abs() < 1e-6);
+        assert!((film.mean_relative_error() - 0.5 * relative).abs() < 1e-3);
+        for _ in 0..300 {
+            film.add_sample(0, 0, gray(0.0));
+            film.add_sample(0, 0, gray(2.0));
+        }
+        assert!(film.error(0, 0) < 0.5 * error);
+        assert_eq!(700, film.samples(0, 0));
+        assert_eq!(800, film.total_samples());
+    }
+
+    #[test]
+    fn relative_error_scales_with_brightness() {
+        let mut film = Film::new(2, 1);
+        for i in 0..100 {
+            film.add_sample(0, 0, gray(2.0 * (i % 2) as f32));
+            film.add_sample(1, 0, gray(200.0 * (i % 2) as f32));
+        }
+        assert!((film.relative_error(0, 0) - film.relative_error(1, 0)).abs() < 1e-4);
+        assert!(film.error(1, 0) > 9.0 * film.error(0, 0));
+    }
+
+    #[test]
+    fn invalid_samples_never_converge() {
+        let mut film = Film::new(1, 1);
+        for _ in 0..10 {
+            film.add_sample(0, 0, gray(1.0));
+        }
+        film.add_sample(0, 0, gray(f32::NAN));
+        assert_eq!(f32::INFINITY, film.error(0, 0));
+        assert_eq!(f32::INFINITY, film.mean_relative_error());
+    }
+}
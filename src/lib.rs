@@ -0,0 +1,20 @@
+pub mod bump;
+pub mod bvh;
+pub mod checkpoint;
+pub mod environment;
+pub mod film;
+pub mod light;
+pub mod material;
+pub mod math;
+pub mod microfacet;
+pub mod noise;
+pub mod obj;
+pub mod output;
+pub mod preview;
+pub mod render;
+pub mod sampler;
+pub mod scene;
+pub mod scene_file;
+pub mod sky;
+pub mod spectrum;
+pub mod texture;
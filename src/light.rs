@@ -0,0 +1,183 @@
+//! Lights without area, which rays can never hit and only direct light
+//! sampling finds, giving hard-edged shadows.
+
+use crate::material::Color;
+use crate::math::*;
+
+pub enum Light {
+    /// Shining equally in all directions, `intensity` being the irradiance
+    /// at unit distance, falling off with the square of it.
+    Point { position: Point, intensity: Color },
+    /// A point light shining only into a cone around `direction`, fading
+    /// out towards its edge.
+    Spot {
+        position: Point,
+        direction: Vector,
+        intensity: Color,
+        // Cosines of the angles from the axis where the fading starts and
+        // where the light is gone.
+        cos_falloff: f32,
+        cos_cutoff: f32,
+    },
+    /// Parallel light travelling along `direction`, from something far away
+    /// like the sun, with `irradiance` on surfaces facing it.
+    Directional {
+        direction: Vector,
+        irradiance: Color,
+    },
+}
+
+impl Light {
+    /// Spot light with its cone `angle` from the axis, fading over the outer
+    /// `falloff` of it, both in radians.
+    pub fn spot(
+        position: Point,
+        direction: Vector,
+        intensity: Color,
+        angle: f32,
+        falloff: f32,
+    ) -> Light {
+        Light::Spot {
+            position,
+            direction: direction.normalize(),
+            intensity,
+            cos_falloff: (angle - falloff).cos(),
+            cos_cutoff: angle.cos(),
+        }
+    }
+
+    pub fn directional(direction: Vector, irradiance: Color) -> Light {
+        Light::Directional {
+            direction: direction.normalize(),
+            irradiance,
+        }
+    }
+
+    /// Direction towards the light from `from`, its distance and the
+    /// irradiance it gives a surface facing it there, unless it is dark.
+    pub fn illuminate(&self, from: Point) -> Option<(Vector, f32, Color)> {
+        let (direction, distance, irradiance) = match *self {
+            Light::Point {
+                position,
+                intensity,
+            } => {
+                let to_light = position - from;
+                let d2 = to_light.square_length();
+                (to_light.normalize(), d2.sqrt(), intensity * (1.0 / d2))
+            }
+            Light::Spot {
+                position,
+                direction,
+                intensity,
+                cos_falloff,
+                cos_cutoff,
+            } => {
+                let to_light = position - from;
+                let d2 = to_light.square_length();
+                let towards = to_light.normalize();
+                let cos_theta = dot(-towards, direction);
+                let fade = smoothstep(cos_cutoff, cos_falloff, cos_theta);
+                (towards, d2.sqrt(), intensity * (fade / d2))
+            }
+            Light::Directional {
+                direction,
+                irradiance,
+            } => (-direction, f32::INFINITY, irradiance),
+        };
+        if irradiance.is_black() || distance <= 0.0 {
+            None
+        } else {
+            Some((direction, distance, irradiance))
+        }
+    }
+}
+
+/// Zero below `edge0`, one above `edge1` and smooth in between; a hard step
+/// at `edge0` if the two meet.
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x >= edge0 { 1.0 } else { 0.0 };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Color = Color {
+        red: 1.0,
+        green: 1.0,
+        blue: 1.0,
+    };
+
+    const ORIGIN: Point = Point {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    fn point(x: f32, y: f32, z: f32) -> Point {
+        Point { x, y, z }
+    }
+
+    #[test]
+    fn point_light_falls_off_with_distance() {
+        let light = Light::Point {
+            position: point(0.0, 0.0, 2.0),
+            intensity: WHITE,
+        };
+        let (direction, distance, irradiance) = light.illuminate(ORIGIN).unwrap();
+        assert_eq!(1.0, direction.z);
+        assert_eq!(2.0, distance);
+        assert_eq!(0.25, irradiance.green);
+    }
+
+    #[test]
+    fn spot_light_cone() {
+        let down = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let light = Light::spot(
+            point(0.0, 0.0, 1.0),
+            down,
+            WHITE,
+            30_f32.to_radians(),
+            10_f32.to_radians(),
+        );
+        let lit = |x: f32| {
+            light
+                .illuminate(point(x, 0.0, 0.0))
+                .map(|(_, d, c)| c.red * d * d)
+        };
+        // Full inside 20 degrees, dark past 30 and fading in between.
+        assert!((lit(0.3).unwrap() - 1.0).abs() < 1e-5);
+        assert_eq!(None, lit(0.6));
+        let edge = lit(25_f32.to_radians().tan()).unwrap();
+        assert!(edge > 0.4 && edge < 0.6, "{}", edge);
+
+        let hard = Light::spot(point(0.0, 0.0, 1.0), down, WHITE, 0.5, 0.0);
+        assert!(hard.illuminate(point(0.5, 0.0, 0.0)).is_some());
+        assert!(hard.illuminate(point(0.6, 0.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn directional_light_is_parallel() {
+        let light = Light::directional(
+            Vector {
+                x: 1.0,
+                y: 0.0,
+                z: -1.0,
+            },
+            WHITE,
+        );
+        let (a, distance, _) = light.illuminate(ORIGIN).unwrap();
+        let (b, _, _) = light.illuminate(point(5.0, -3.0, 2.0)).unwrap();
+        assert_eq!(f32::INFINITY, distance);
+        assert_eq!((a.x, a.y, a.z), (b.x, b.y, b.z));
+        assert!((a.z - 0.5_f32.sqrt()).abs() < 1e-6);
+    }
+}
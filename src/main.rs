@@ -1,75 +1,230 @@
-mod material;
-mod math;
-mod preview;
-mod render;
-mod scene;
-
 use clap::Parser;
-use image::ColorType::Rgba8;
-use math::*;
-use std::path::Path;
+use pathtr::checkpoint::{Autosave, Checkpoint, SceneId};
+use pathtr::{film, output, preview, render, sampler, scene_file};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Instant;
-
-const WIDTH: usize = 800;
-const HEIGHT: usize = 500;
-const RAYS_PER_PIXEL: i64 = 1000;
+use std::time::{Duration, Instant};
 
 #[derive(Parser)]
 pub struct Args {
     #[arg(short, long)]
     pub preview: bool,
+    /// Image width in pixels
+    #[arg(long, default_value_t = 800)]
+    pub width: usize,
+    /// Image height in pixels
+    #[arg(long, default_value_t = 500)]
+    pub height: usize,
+    /// Samples per pixel [default: 1000, unlimited with --time-limit or
+    /// --target-error, the checkpoint's with --resume]
+    #[arg(long)]
+    pub spp: Option<u32>,
+    /// Number of render threads, defaults to the number of cores
+    #[arg(long)]
+    pub threads: Option<usize>,
+    /// Seed for the random numbers, random if not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Trace wavelengths instead of RGB, needed for dispersion
+    #[arg(long)]
+    pub spectral: bool,
+    /// Bounces before Russian roulette may end a path
+    #[arg(long, default_value_t = 3)]
+    pub min_depth: u32,
+    /// Bounces after which paths end, 1 gives direct lighting only
+    #[arg(long, default_value_t = 100)]
+    pub max_depth: u32,
+    /// Where the random numbers of each pixel sample come from
+    #[arg(long, value_enum, default_value = "sobol")]
+    pub sampler: sampler::SamplerType,
+    /// Stop sampling pixels once the standard error of their luminance,
+    /// divided by the square root of the luminance, falls below this, e.g.
+    /// 0.01. Dividing by the square root rather than the luminance itself
+    /// weighs noise about as visibly in dark and bright pixels
+    #[arg(long)]
+    pub noise_threshold: Option<f32>,
+    /// Render until this much time has passed, e.g. 90s, 5m or 1h 30m
+    #[arg(long, value_parser = humantime::parse_duration)]
+    pub time_limit: Option<Duration>,
+    /// Render until the standard error of the pixels' luminance, divided by
+    /// the luminance itself and averaged over all pixels, falls below this,
+    /// e.g. 0.02
+    #[arg(long)]
+    pub target_error: Option<f32>,
+    /// Also write an image of the number of samples each pixel got
+    #[arg(long)]
+    pub sample_counts: Option<PathBuf>,
+    /// Scene description to render
+    #[arg(short, long, default_value = "scenes/default.toml")]
+    pub scene: PathBuf,
+    /// Tone mapping operator used for 8-bit output
+    #[arg(long, value_enum, default_value = "aces")]
+    pub tonemap: film::ToneMap,
+    /// Exposure adjustment in stops
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub exposure: f32,
+    /// Where to write the image, .exr, .hdr and .pfm files get linear radiance
+    #[arg(short, long, default_value = "image.png")]
+    pub output: PathBuf,
+    /// Save the state of the render here now and then and on exit, to carry
+    /// on with later using --resume
+    #[arg(long)]
+    pub checkpoint: Option<PathBuf>,
+    /// How often to save checkpoints
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "5m")]
+    pub checkpoint_interval: Duration,
+    /// Carry on with a checkpointed render of the same scene, with its seed
+    /// and samples per pixel and otherwise the same settings, saving
+    /// checkpoints back to it unless --checkpoint is given
+    #[arg(long)]
+    pub resume: Option<PathBuf>,
 }
 
 pub fn parse() -> Args {
     Args::parse()
 }
 
+// Cap on the samples per pixel when rendering to a time limit or target
+// error, keeping sample indices well in range.
+const UNLIMITED_SPP: u32 = 1 << 20;
+
 fn main() {
     let args = Args::parse();
-    let camera = Arc::new(scene::Camera {
-        look_from: Point {
-            x: -0.1,
-            y: -15.0,
-            z: 4.8,
+    let resumed = args
+        .resume
+        .as_ref()
+        .map(|path| match Checkpoint::load(path) {
+            Ok(checkpoint) => (path, checkpoint),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        });
+    let spp = match (args.spp, &resumed) {
+        (Some(spp), _) => spp,
+        (None, Some((_, checkpoint))) => checkpoint.samples_per_pixel,
+        (None, None) if args.time_limit.is_some() || args.target_error.is_some() => UNLIMITED_SPP,
+        (None, None) => 1000,
+    };
+    if args.width == 0 || args.height == 0 || spp == 0 || args.threads == Some(0) {
+        eprintln!("Width, height, samples per pixel and threads must be positive");
+        std::process::exit(1);
+    }
+    if args.max_depth == 0 {
+        eprintln!("Maximum depth must be positive");
+        std::process::exit(1);
+    }
+    if matches!(args.sampler, sampler::SamplerType::Stratified)
+        && args.spp.is_none()
+        && resumed.is_none()
+        && (args.time_limit.is_some() || args.target_error.is_some())
+    {
+        // Strata are sized for the samples per pixel, so without a count
+        // they would be too fine to stratify anything.
+        eprintln!("The stratified sampler needs --spp with --time-limit or --target-error");
+        std::process::exit(1);
+    }
+    let (scene, camera) = match scene_file::load(&args.scene) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let scene = Arc::new(scene);
+    let camera = Arc::new(camera);
+    let scene_id = match SceneId::of(&args.scene) {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("{}: {}", args.scene.display(), e);
+            std::process::exit(1);
+        }
+    };
+
+    let autosave = args
+        .checkpoint
+        .clone()
+        .or_else(|| args.resume.clone())
+        .map(|path| Autosave {
+            path,
+            interval: args.checkpoint_interval,
+        });
+
+    let settings = render::Settings {
+        width: args.width,
+        height: args.height,
+        samples_per_pixel: spp,
+        threads: args
+            .threads
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get())),
+        seed: resumed.as_ref().map_or_else(
+            || args.seed.unwrap_or_else(rand::random),
+            |(_, checkpoint)| checkpoint.seed,
+        ),
+        spectral: args.spectral,
+        depth: render::Depth {
+            min: args.min_depth,
+            max: args.max_depth,
         },
-        direction: (Vector {
-            x: 0.05,
-            y: 1.0,
-            z: -0.25,
-        })
-        .normalize(),
-        up: Vector {
-            x: 0.0,
-            y: 0.0,
-            z: 1.0,
+        sampler: args.sampler,
+        noise_threshold: args.noise_threshold,
+        time_limit: args.time_limit,
+        target_error: args.target_error,
+        tone_mapping: film::ToneMapping {
+            operator: args.tonemap,
+            exposure: args.exposure,
         },
-        fov: 3.14 / 4.0,
-        aspect: 1.6,
-        aperture: 0.3,
-        focal_distance: 16.0,
-    });
-    let scene = Arc::new(prep_scene());
+    };
+
+    let checkpoint = match resumed {
+        Some((path, checkpoint)) => {
+            if let Some(difference) = checkpoint.mismatch(&settings, &scene_id) {
+                eprintln!(
+                    "{}: {}, resume it with the same scene and settings",
+                    path.display(),
+                    difference
+                );
+                std::process::exit(1);
+            }
+            checkpoint
+        }
+        None => Checkpoint::new(&settings, scene_id),
+    };
 
-    let width = WIDTH;
-    let height = HEIGHT;
+    println!("Seed: {}", settings.seed);
 
     let preview_window = if args.preview {
-        Some(preview::open_window(WIDTH, HEIGHT).unwrap())
+        Some(preview::open_window(settings.width, settings.height).unwrap())
     } else {
         None
     };
 
+    // Ctrl-C stops rendering and still writes out everything, a second one
+    // doesn't wait for that.
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = Arc::clone(&stop);
+    if let Err(e) = ctrlc::set_handler(move || {
+        if handler_stop.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    }) {
+        eprintln!("Can't catch Ctrl-C: {}", e);
+    }
+
+    let resumed_samples = checkpoint.film.total_samples();
     let start = Instant::now();
-    let img_buffer = render::render(
+    let checkpoint = render::render(
         &preview_window,
         scene,
         camera,
-        width,
-        height,
-        RAYS_PER_PIXEL,
+        &settings,
+        checkpoint,
+        autosave.as_ref(),
+        &stop,
     );
     let total = start.elapsed().as_millis();
+    let film = &checkpoint.film;
 
     if let Some(p) = preview_window {
         p.wait();
@@ -78,150 +233,25 @@ fn main() {
     println!("Time: {} ms", total);
     println!(
         "Rays per ms: {}",
-        RAYS_PER_PIXEL as usize * width * height / total as usize
-    );
-    image::save_buffer(
-        &Path::new("image.png"),
-        &img_buffer[..],
-        WIDTH as u32,
-        HEIGHT as u32,
-        Rgba8,
-    )
-    .unwrap();
-    println!("Wrote image.png");
-}
-
-fn prep_scene() -> scene::Scene {
-    let mut scene = scene::Scene::new();
-    let white = material::Color {
-        red: 1.0,
-        green: 1.0,
-        blue: 1.0,
-    };
-
-    let p1 = Plane {
-        point: Point {
-            x: 0.0,
-            y: 0.0,
-            z: 0.0,
-        },
-        normal: Vector {
-            x: 0.0,
-            y: 0.0,
-            z: 1.0,
-        },
-    };
-    let m1 = material::Material::create(white * 0.8, 1.0, 0.0);
-    let obj1 = scene::Object {
-        shape: Box::new(p1),
-        material: m1,
-    };
-    scene.objs.push(obj1);
-
-    add_sphere(
-        &mut scene,
-        0.1,
-        -0.03,
-        1.0,
-        material::Material::create_colored_1(),
-    );
-    add_sphere(
-        &mut scene,
-        -1.7,
-        -1.0,
-        0.7,
-        material::Material::create_colored_2(),
-    );
-    add_sphere(
-        &mut scene,
-        2.0,
-        0.3,
-        1.0,
-        material::Material::create_glass(),
-    );
-
-    add_sphere(
-        &mut scene,
-        -1.0,
-        -3.3,
-        0.4,
-        material::Material::create_colored_3(),
-    );
-    add_sphere(
-        &mut scene,
-        1.2,
-        -3.3,
-        0.4,
-        material::Material::create_colored_2(),
-    );
-
-    add_sphere(
-        &mut scene,
-        2.2,
-        5.3,
-        0.8,
-        material::Material::create_colored_2(),
+        (film.total_samples() - resumed_samples) / (total as u64).max(1)
     );
-    add_sphere(
-        &mut scene,
-        -2.0,
-        4.3,
-        1.1,
-        material::Material::create_colored_1(),
-    );
-
-    add_sphere(
-        &mut scene,
-        5.2,
-        15.3,
-        1.0,
-        material::Material::create_colored_1(),
-    );
-    add_sphere(
-        &mut scene,
-        -0.2,
-        10.3,
-        1.0,
-        material::Material::create_colored_3(),
-    );
-
-    // Lights
-    scene.objs.push(scene::Object {
-        shape: Box::new(Sphere {
-            center: Point {
-                x: 20.3,
-                y: -20.0,
-                z: 20.35,
-            },
-            radius: 5.0,
-        }),
-        material: material::Material::create_emissive(white * 5.0),
-    });
-    scene.objs.push(scene::Object {
-        shape: Box::new(Sphere {
-            center: Point {
-                x: -20.0,
-                y: -5.0,
-                z: 10.35,
-            },
-            radius: 4.0,
-        }),
-        material: material::Material::create_emissive(white * 2.0),
-    });
-
-    return scene;
-}
-
-fn add_sphere(scene: &mut scene::Scene, x: f32, y: f32, radius: f32, material: material::Material) {
-    scene.objs.push(scene::Object {
-        shape: Box::new(Sphere {
-            center: Point {
-                x: 1.5 * x,
-                y: 1.5 * y,
-                z: radius,
-            },
-            radius,
-        }),
-        material,
-    });
+    if let Some(autosave) = &autosave {
+        if let Err(e) = checkpoint.save(&autosave.path) {
+            eprintln!("{}: {}", autosave.path.display(), e);
+            std::process::exit(1);
+        }
+        println!("Wrote {}", autosave.path.display());
+    }
+    if let Err(e) = output::save(&args.output, film, &settings.tone_mapping) {
+        eprintln!("{}: {}", args.output.display(), e);
+        std::process::exit(1);
+    }
+    println!("Wrote {}", args.output.display());
+    if let Some(path) = args.sample_counts {
+        if let Err(e) = output::save_sample_counts(&path, film) {
+            eprintln!("{}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+        println!("Wrote {}", path.display());
+    }
 }
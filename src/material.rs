@@ -1,10 +1,14 @@
 use std::ops::Add;
 use std::ops::AddAssign;
 use std::ops::Mul;
+use std::sync::Arc;
 
-use rand::Rng;
-
+use crate::bump::Bump;
 use crate::math::*;
+use crate::microfacet;
+use crate::sampler::Sampler;
+use crate::spectrum::Channels;
+use crate::texture::Texture;
 
 #[derive(Copy, Clone)]
 pub struct Color {
@@ -13,6 +17,21 @@ pub struct Color {
     pub blue: f32,
 }
 
+impl Color {
+    pub fn is_black(&self) -> bool {
+        self.red == 0.0 && self.green == 0.0 && self.blue == 0.0
+    }
+
+    pub fn max_component(&self) -> f32 {
+        self.red.max(self.green).max(self.blue)
+    }
+
+    /// Brightness as perceived, for linear sRGB.
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.red + 0.7152 * self.green + 0.0722 * self.blue
+    }
+}
+
 impl Mul for Color {
     type Output = Color;
     fn mul(self, rhs: Color) -> Color {
@@ -56,12 +75,134 @@ impl AddAssign for Color {
     }
 }
 
+#[derive(Clone)]
 pub struct Material {
-    diffuse: Color,
+    diffuse: Arc<dyn Texture>,
     // Emissivity takes priority
-    emissive: Color,
+    emissive: Option<Arc<dyn Texture>>,
     ior: f32,
     transparency: f32,
+    // Microfacet roughness of the reflecting surface, zero is a perfect mirror.
+    roughness: f32,
+    // Conductors tint their reflections and have no diffuse base.
+    conductor: Option<Conductor>,
+    // Absorption coefficient per unit distance of light travelling inside.
+    absorption: Color,
+    // Wavelength dependent index of refraction, used instead of `ior` when
+    // rendering spectrally.
+    dispersion: Option<Dispersion>,
+    // Normal or bump map.
+    bump: Option<Bump>,
+}
+
+/// Index of refraction as a function of wavelength, both models taking the
+/// wavelength in micrometers.
+#[derive(Copy, Clone)]
+pub enum Dispersion {
+    /// n = a + b / w^2
+    Cauchy { a: f32, b: f32 },
+    /// n^2 = 1 + sum of b_i w^2 / (w^2 - c_i)
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl Dispersion {
+    /// Index of refraction at a wavelength in nanometers.
+    pub fn ior(&self, wavelength: f32) -> f32 {
+        let w2 = (wavelength * 1e-3).powi(2);
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / w2,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = b.iter().zip(c.iter()).map(|(b, c)| b * w2 / (w2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+}
+
+/// Wavelength of the sodium D line at which indices of refraction are
+/// usually quoted, in nanometers.
+const D_LINE: f32 = 589.3;
+
+/// Complex index of refraction `eta + i k` of a metal, per color channel.
+#[derive(Copy, Clone)]
+pub struct Conductor {
+    pub eta: Color,
+    pub k: Color,
+}
+
+const fn rgb(red: f32, green: f32, blue: f32) -> Color {
+    Color { red, green, blue }
+}
+
+/// Measured optical constants of some metals, sampled at 650, 550 and 450 nm.
+pub const METALS: &[(&str, Conductor)] = &[
+    (
+        "aluminium",
+        Conductor {
+            eta: rgb(1.657, 0.880, 0.521),
+            k: rgb(9.224, 6.270, 4.837),
+        },
+    ),
+    (
+        "chromium",
+        Conductor {
+            eta: rgb(3.107, 3.181, 2.323),
+            k: rgb(3.331, 3.329, 3.135),
+        },
+    ),
+    (
+        "copper",
+        Conductor {
+            eta: rgb(0.200, 0.924, 1.102),
+            k: rgb(3.912, 2.452, 2.142),
+        },
+    ),
+    (
+        "gold",
+        Conductor {
+            eta: rgb(0.143, 0.374, 1.442),
+            k: rgb(3.983, 2.385, 1.603),
+        },
+    ),
+    (
+        "iron",
+        Conductor {
+            eta: rgb(2.911, 2.950, 2.585),
+            k: rgb(3.089, 2.932, 2.767),
+        },
+    ),
+    (
+        "silver",
+        Conductor {
+            eta: rgb(0.155, 0.117, 0.138),
+            k: rgb(4.828, 3.122, 2.147),
+        },
+    ),
+    (
+        "titanium",
+        Conductor {
+            eta: rgb(2.741, 2.542, 2.267),
+            k: rgb(3.814, 3.435, 3.039),
+        },
+    ),
+];
+
+impl Conductor {
+    /// Looks up one of the `METALS` by name.
+    pub fn named(name: &str) -> Option<Conductor> {
+        METALS.iter().find(|(n, _)| *n == name).map(|(_, c)| *c)
+    }
+
+    /// Fresnel reflectance for light arriving at `cos_theta` from the normal.
+    pub fn reflectance(&self, cos_theta: f32, channels: &Channels) -> Color {
+        let eta = channels.color(self.eta);
+        let k = channels.color(self.k);
+        Color {
+            red: fresnel_conductor(cos_theta, eta.red, k.red),
+            green: fresnel_conductor(cos_theta, eta.green, k.green),
+            blue: fresnel_conductor(cos_theta, eta.blue, k.blue),
+        }
+    }
 }
 
 pub struct LightRay {
@@ -69,97 +210,146 @@ pub struct LightRay {
     pub light: Color,
     pub ior: f32,
     pub count: i32,
-    pub done: bool,
+    // Whether the last bounce was a perfect reflection or refraction, which
+    // direct light sampling can't produce.
+    pub specular: bool,
+    // Solid angle density of the last bounce direction.
+    pub pdf: f32,
+    pub channels: Channels,
 }
 
 impl Material {
-    pub fn create(diffuse: Color, ior: f32, transparency: f32) -> Material {
+    pub fn create(diffuse: impl Into<Arc<dyn Texture>>, ior: f32, transparency: f32) -> Material {
         Material {
-            diffuse,
+            diffuse: diffuse.into(),
             ior,
             transparency,
-            emissive: Color {
+            roughness: 0.0,
+            conductor: None,
+            absorption: Color {
                 red: 0.0,
                 green: 0.0,
                 blue: 0.0,
             },
+            dispersion: None,
+            emissive: None,
+            bump: None,
         }
     }
 
-    pub fn create_emissive(emissive: Color) -> Material {
+    pub fn create_emissive(emissive: impl Into<Arc<dyn Texture>>) -> Material {
         Material {
-            diffuse: Color {
+            diffuse: Arc::new(Color {
                 red: 0.0,
                 green: 0.0,
                 blue: 0.0,
-            },
+            }),
             ior: 1.0,
             transparency: 0.0,
-            emissive,
-        }
-    }
-
-    pub fn create_glass() -> Material {
-        Material {
-            diffuse: Color {
-                red: 0.0,
-                green: 0.0,
-                blue: 0.0,
-            },
-            ior: 1.5,
-            transparency: 1.0,
-            emissive: Color {
+            roughness: 0.0,
+            conductor: None,
+            absorption: Color {
                 red: 0.0,
                 green: 0.0,
                 blue: 0.0,
             },
+            dispersion: None,
+            emissive: Some(emissive.into()),
+            bump: None,
         }
     }
 
-    pub fn create_colored_1() -> Material {
+    /// Clear dielectric letting through `transmittance` of the light over
+    /// `distance` travelled inside it.
+    pub fn create_dielectric(ior: f32, transmittance: Color, distance: f32) -> Material {
+        // Beer-Lambert: transmittance = exp(-absorption * distance).
+        let absorption = |t: f32| -t.max(1e-6).ln() / distance;
         Material {
-            diffuse: Color {
-                red: 0.2,
-                green: 1.0,
-                blue: 1.0,
-            },
-            ior: 1.5,
-            transparency: 0.0,
-            emissive: Color {
-                red: 0.0,
-                green: 0.0,
-                blue: 0.0,
+            absorption: Color {
+                red: absorption(transmittance.red),
+                green: absorption(transmittance.green),
+                blue: absorption(transmittance.blue),
             },
+            ..Material::create(
+                Color {
+                    red: 0.0,
+                    green: 0.0,
+                    blue: 0.0,
+                },
+                ior,
+                1.0,
+            )
         }
     }
 
-    pub fn create_colored_2() -> Material {
+    /// Clear dielectric like `create_dielectric`, with an index of refraction
+    /// depending on the wavelength.
+    pub fn create_dispersive(
+        dispersion: Dispersion,
+        transmittance: Color,
+        distance: f32,
+    ) -> Material {
         Material {
-            diffuse: Color {
-                red: 1.0,
-                green: 0.8,
-                blue: 0.2,
-            },
-            ior: 1.5,
-            transparency: 0.0,
-            emissive: Color {
-                red: 0.0,
-                green: 0.0,
-                blue: 0.0,
-            },
+            dispersion: Some(dispersion),
+            ..Material::create_dielectric(dispersion.ior(D_LINE), transmittance, distance)
+        }
+    }
+
+    /// Diffuse base under a dielectric coat with rough reflections.
+    pub fn create_glossy(
+        diffuse: impl Into<Arc<dyn Texture>>,
+        ior: f32,
+        roughness: f32,
+    ) -> Material {
+        Material {
+            roughness,
+            ..Material::create(diffuse, ior, 0.0)
+        }
+    }
+
+    /// Metal with the given optical constants, see `Conductor::named` for
+    /// some real ones.
+    pub fn create_conductor(conductor: Conductor, roughness: f32) -> Material {
+        let black = Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        };
+        Material {
+            roughness,
+            conductor: Some(conductor),
+            ..Material::create(black, 1.0, 0.0)
         }
     }
 
-    pub fn create_colored_3() -> Material {
+    /// The same material with its shading normals perturbed.
+    pub fn with_bump(self, bump: Bump) -> Material {
         Material {
-            diffuse: Color {
-                red: 0.9,
-                green: 0.6,
-                blue: 1.0,
+            bump: Some(bump),
+            ..self
+        }
+    }
+
+    /// The hit as this material sees it, with the shading normal given by its
+    /// normal or bump map.
+    pub fn bumped(&self, hit: Intersection) -> Intersection {
+        match &self.bump {
+            Some(bump) => Intersection {
+                shading_normal: bump.normal(&hit),
+                ..hit
             },
-            ior: 1.5,
-            transparency: 0.0,
-            emissive: Color {
+            None => hit,
+        }
+    }
+
+    pub fn is_emissive(&self) -> bool {
+        self.emissive.is_some()
+    }
+
+    pub fn emission(&self, hit: &Intersection, channels: &Channels) -> Color {
+        match &self.emissive {
+            Some(emissive) => channels.color(emissive.value(hit)),
+            None => Color {
                 red: 0.0,
                 green: 0.0,
                 blue: 0.0,
@@ -167,63 +357,283 @@ impl Material {
         }
     }
 
-    pub fn new_ray<R: Rng + ?Sized>(
+    /// Fraction of light left after travelling `distance` inside.
+    pub fn transmittance(&self, distance: f32, channels: &Channels) -> Color {
+        let absorption = channels.color(self.absorption);
+        Color {
+            red: (-absorption.red * distance).exp(),
+            green: (-absorption.green * distance).exp(),
+            blue: (-absorption.blue * distance).exp(),
+        }
+    }
+
+    fn is_smooth(&self) -> bool {
+        self.roughness < microfacet::MIN_ROUGHNESS
+    }
+
+    /// Whether all light leaving the surface is reflected or refracted
+    /// perfectly, with nothing `eval` covers for direct light sampling.
+    pub fn is_specular(&self) -> bool {
+        self.is_smooth() && (self.conductor.is_some() || self.transparency >= 1.0)
+    }
+
+    /// Scatters the incoming ray. Emissive materials terminate paths and are
+    /// handled by the caller.
+    pub fn new_ray<S: Sampler + ?Sized>(
         &self,
         ray: LightRay,
-        point: Point,
-        normal: Vector,
-        inside: bool,
-        mut rng: &mut R,
+        hit: &Intersection,
+        sampler: &mut S,
     ) -> LightRay {
+        let Intersection {
+            point,
+            normal: geometric,
+            shading_normal: normal,
+            inside,
+            ..
+        } = *hit;
         let incoming_direction = ray.ray.direction;
         let cos_theta = -dot(incoming_direction, normal);
-        if self.emissive.red != 0.0 || self.emissive.blue != 0.0 || self.emissive.green != 0.0 {
+        let channels = ray.channels;
+        // Shading normals can send reflections below the actual surface, those
+        // paths end there.
+        let above = |light: Color, direction: Vector| {
+            if dot(direction, geometric) > 0.0 {
+                light
+            } else {
+                light * 0.0
+            }
+        };
+        if let Some(conductor) = self.conductor {
+            if self.is_smooth() {
+                let direction = reflection(incoming_direction, normal);
+                return LightRay {
+                    ray: Ray {
+                        origin: offset_origin(point, geometric, geometric),
+                        direction,
+                    },
+                    light: above(
+                        ray.light * conductor.reflectance(cos_theta, &channels),
+                        direction,
+                    ),
+                    ior: ray.ior,
+                    count: ray.count + 1,
+                    specular: true,
+                    pdf: 0.0,
+                    channels,
+                };
+            }
+            let direction = self.sample_microfacet(incoming_direction, normal, sampler);
+            return self.scatter(ray, hit, direction);
+        }
+        if !self.is_smooth() {
+            let direction = if sampler.get_1d() < coat_reflectance(self.ior, cos_theta) {
+                self.sample_microfacet(incoming_direction, normal, sampler)
+            } else {
+                cosine_direction(normal, sampler)
+            };
+            return self.scatter(ray, hit, direction);
+        }
+
+        // Dispersion splits the wavelengths up, only the hero one can follow.
+        let (ior, channels, light) = match (self.dispersion, channels.hero_wavelength()) {
+            (Some(dispersion), Some(wavelength)) => {
+                let (channels, light) = channels.keep_hero(ray.light);
+                (dispersion.ior(wavelength), channels, light)
+            }
+            _ => (self.ior, channels, ray.light),
+        };
+        // Leaving the object goes back into the air.
+        let out_ior = if inside { 1.0 } else { ior };
+        if sampler.get_1d() < reflection_coefficient(ray.ior, out_ior, cos_theta) {
+            let direction = reflection(incoming_direction, normal);
             LightRay {
                 ray: Ray {
-                    origin: point,
-                    direction: normal,
+                    origin: offset_origin(point, geometric, geometric),
+                    direction,
                 },
-                light: ray.light * self.emissive,
+                light: above(light, direction),
                 ior: ray.ior,
                 count: ray.count + 1,
-                done: true,
+                specular: true,
+                pdf: 0.0,
+                channels,
             }
-        } else if rng.gen::<f32>() < reflection_coefficient(ray.ior, self.ior, cos_theta) {
+        } else if self.transparency > 0. && sampler.get_1d() < self.transparency {
+            let direction = refraction(ray.ior, out_ior, incoming_direction, normal);
             LightRay {
                 ray: Ray {
-                    origin: point,
-                    direction: reflection(incoming_direction, normal),
+                    origin: offset_origin(point, geometric, direction),
+                    direction,
                 },
-                light: ray.light,
-                ior: ray.ior,
-                count: ray.count + 1,
-                done: false,
-            }
-        } else if self.transparency > 0. && rng.gen::<f32>() < self.transparency {
-            let mut new_ray = LightRay {
-                ray: Ray {
-                    origin: point,
-                    direction: refraction(ray.ior, self.ior, incoming_direction, normal),
+                light,
+                ior: if dot(direction, geometric) < 0.0 {
+                    out_ior
+                } else {
+                    // Totally reflected.
+                    ray.ior
                 },
-                light: ray.light,
-                ior: if inside { 1.0 } else { self.ior },
                 count: ray.count + 1,
-                done: false,
-            };
-            new_ray.ray.origin = translate(new_ray.ray.origin, 1e-8 * new_ray.ray.direction);
-            new_ray
+                specular: true,
+                pdf: 0.0,
+                channels,
+            }
         } else {
+            let direction = cosine_direction(normal, sampler);
+            let (_, pdf) = self.eval(incoming_direction, direction, hit, &channels);
             LightRay {
-                ray: generate_half_sphere_ray(point, normal, &mut rng),
-                light: self.diffuse * ray.light,
+                ray: Ray {
+                    origin: offset_origin(point, geometric, geometric),
+                    direction,
+                },
+                // f * cos / pdf, where the cosine, 1/pi and the probability
+                // of picking the diffuse lobe cancel out.
+                light: above(channels.color(self.diffuse.value(hit)) * light, direction),
                 ior: ray.ior,
                 count: ray.count + 1,
-                done: false,
+                specular: false,
+                pdf,
+                channels,
+            }
+        }
+    }
+
+    /// Reflects `incoming` off a visible microfacet normal.
+    fn sample_microfacet<S: Sampler + ?Sized>(
+        &self,
+        incoming: Vector,
+        normal: Vector,
+        sampler: &mut S,
+    ) -> Vector {
+        let frame = Frame::from_normal(normal);
+        let alpha = microfacet::alpha(self.roughness);
+        let i = frame.to_local(-incoming);
+        let (u1, u2) = sampler.get_2d();
+        let h = microfacet::sample_visible_normal(alpha, i, u1, u2);
+        frame.to_world(microfacet::reflect(i, h))
+    }
+
+    /// Continues a path along a direction sampled from the non-specular lobes.
+    fn scatter(&self, ray: LightRay, hit: &Intersection, direction: Vector) -> LightRay {
+        let (f, pdf) = self.eval(ray.ray.direction, direction, hit, &ray.channels);
+        let cos_out = dot(direction, hit.shading_normal);
+        let light = if pdf > 0.0 && cos_out > 0.0 && dot(direction, hit.normal) > 0.0 {
+            f * ray.light * (cos_out / pdf)
+        } else {
+            // Reflected below the surface.
+            Color {
+                red: 0.0,
+                green: 0.0,
+                blue: 0.0,
+            }
+        };
+        LightRay {
+            ray: Ray {
+                origin: offset_origin(hit.point, hit.normal, direction),
+                direction,
+            },
+            light,
+            ior: ray.ior,
+            count: ray.count + 1,
+            specular: false,
+            pdf,
+            channels: ray.channels,
+        }
+    }
+
+    /// BSDF value and sampling density of the non-specular lobes for a path
+    /// arriving along `incoming` and continuing along `outgoing`.
+    pub fn eval(
+        &self,
+        incoming: Vector,
+        outgoing: Vector,
+        hit: &Intersection,
+        channels: &Channels,
+    ) -> (Color, f32) {
+        let normal = hit.shading_normal;
+        let black = Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        };
+        let cos_in = -dot(incoming, normal);
+        let cos_out = dot(outgoing, normal);
+        if cos_out <= 0.0 {
+            return (black, 0.0);
+        }
+
+        let microfacet = || {
+            let frame = Frame::from_normal(normal);
+            let alpha = microfacet::alpha(self.roughness);
+            let i = frame.to_local(-incoming);
+            let o = frame.to_local(outgoing);
+            if i.z <= 0.0 {
+                return None;
+            }
+            let h = (i + o).normalize();
+            let f = microfacet::distribution(alpha, h) * microfacet::smith_g2(alpha, i, o)
+                / (4.0 * i.z * o.z);
+            Some((f, dot(o, h), microfacet::reflection_pdf(alpha, i, o)))
+        };
+
+        if let Some(conductor) = self.conductor {
+            if self.is_smooth() {
+                return (black, 0.0);
+            }
+            return match microfacet() {
+                Some((f, cos_h, pdf)) => (conductor.reflectance(cos_h, channels) * f, pdf),
+                None => (black, 0.0),
+            };
+        }
+
+        // The coat reflects some light, of the rest a part is transmitted
+        // and the remainder scatters diffusely off the base.
+        let coat = coat_reflectance(self.ior, cos_in);
+        let diffuse = (1.0 - coat) * (1.0 - self.transparency);
+        let mut f =
+            channels.color(self.diffuse.value(hit)) * (diffuse * std::f32::consts::FRAC_1_PI);
+        let mut pdf = diffuse * cos_out * std::f32::consts::FRAC_1_PI;
+        if !self.is_smooth() {
+            if let Some((spec, cos_h, spec_pdf)) = microfacet() {
+                let r = spec * coat_reflectance(self.ior, cos_h);
+                f += Color {
+                    red: r,
+                    green: r,
+                    blue: r,
+                };
+                pdf += coat * spec_pdf;
             }
         }
+        (f, pdf)
     }
 }
 
+/// Fresnel reflectance of a dielectric coat seen from outside.
+fn coat_reflectance(ior: f32, cos_theta: f32) -> f32 {
+    reflection_coefficient(1.0, ior, cos_theta.clamp(0.0, 1.0))
+}
+
+/// Exact Fresnel reflectance of unpolarized light on a conductor with the
+/// complex index of refraction `eta + i k`, seen from a vacuum.
+fn fresnel_conductor(cos_theta: f32, eta: f32, k: f32) -> f32 {
+    let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let eta2 = eta * eta;
+    let k2 = k * k;
+
+    let t0 = eta2 - k2 - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta2 * k2).sqrt();
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let rs = (t1 - t2) / (t1 + t2);
+
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
+}
+
 fn refraction(in_ior: f32, out_ior: f32, in_direction: Vector, normal: Vector) -> Vector {
     let r = in_ior / out_ior;
     let cos_theta = -dot(normal, in_direction);
@@ -248,32 +658,25 @@ fn reflection_coefficient(in_ior: f32, out_ior: f32, cos_theta: f32) -> f32 {
     return r0 + (1. - r0) * f32::powi(1. - cos_theta, 5);
 }
 
-fn generate_half_sphere_ray<R: Rng + ?Sized>(start: Point, normal: Vector, rng: &mut R) -> Ray {
-    // uniform sample over half sphere
-    loop {
-        let x = 2.0 * rng.gen::<f32>() - 1.0;
-        let y = 2.0 * rng.gen::<f32>() - 1.0;
-        let z = 2.0 * rng.gen::<f32>() - 1.0;
-        let v = Vector { x, y, z };
-        if v.square_length() < 1.0 {
-            if dot(v, normal) > 0.0 {
-                return Ray {
-                    origin: start,
-                    direction: v.normalize(),
-                };
-            } else {
-                return Ray {
-                    origin: start,
-                    direction: -v.normalize(),
-                };
-            }
-        }
-    }
+/// Cosine weighted direction around `normal` using Malley's method: uniform
+/// points on the unit disk projected up onto the hemisphere.
+fn cosine_direction<S: Sampler + ?Sized>(normal: Vector, sampler: &mut S) -> Vector {
+    let (u1, u2) = sampler.get_2d();
+    let r = u1.sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u2;
+    // May round down to zero, a grazing direction `eval` gives no weight.
+    let z = (1.0 - r * r).max(0.0).sqrt();
+    Frame::from_normal(normal).to_world(Vector {
+        x: r * phi.cos(),
+        y: r * phi.sin(),
+        z,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sampler::Independent;
 
     #[test]
     fn refraction_straight_test() {
@@ -346,4 +749,143 @@ mod tests {
         assert!(-in_direction.x - res.x < 1e-9);
         //assert!(res.y < in_direction.y);
     }
+
+    #[test]
+    fn cosine_direction_distribution() {
+        let mut sampler = Independent::new(3);
+        let normal = Vector {
+            x: 0.0,
+            y: -0.6,
+            z: 0.8,
+        };
+        let n = 100000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let direction = cosine_direction(normal, &mut sampler);
+            let cos_theta = dot(direction, normal);
+            assert!(cos_theta > 0.0);
+            assert!((direction.length() - 1.0).abs() < 1e-4);
+            sum += cos_theta as f64;
+        }
+        // The mean cosine under a cosine weighted density is 2/3.
+        assert!((sum / n as f64 - 2.0 / 3.0).abs() < 0.005);
+    }
+
+    fn mean_reflected(material: &Material, cos_theta: f32) -> f32 {
+        let mut sampler = Independent::new(4);
+        let normal = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let incoming = Vector {
+            x: (1.0 - cos_theta * cos_theta).sqrt(),
+            y: 0.0,
+            z: -cos_theta,
+        };
+        let n = 100000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let ray = LightRay {
+                ray: Ray {
+                    origin: Point {
+                        x: 0.0,
+                        y: 0.0,
+                        z: 0.0,
+                    },
+                    direction: incoming,
+                },
+                light: Color {
+                    red: 1.0,
+                    green: 1.0,
+                    blue: 1.0,
+                },
+                ior: 1.0,
+                count: 0,
+                specular: false,
+                pdf: 0.0,
+                channels: Channels::Rgb,
+            };
+            let hit = Intersection {
+                point: Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                normal,
+                shading_normal: normal,
+                distance: 1.0,
+                inside: false,
+                uv: [0.0, 0.0],
+                dpdu: normal,
+                dpdv: normal,
+                local: normal,
+            };
+            let res = material.new_ray(ray, &hit, &mut sampler);
+            assert!(res.light.red >= 0.0 && res.light.red.is_finite());
+            sum += res.light.red as f64;
+        }
+        (sum / n as f64) as f32
+    }
+
+    #[test]
+    fn rough_conductor_conserves_energy() {
+        // Close to a perfect reflector in the red channel.
+        let silver = Conductor::named("silver").unwrap();
+        for &roughness in [0.1, 0.5, 1.0].iter() {
+            let material = Material::create_conductor(silver, roughness);
+            for &cos_theta in [1.0, 0.5, 0.1].iter() {
+                let albedo = mean_reflected(&material, cos_theta);
+                assert!(albedo <= 1.0, "{} {}", roughness, albedo);
+                // Single scattering loses energy at high roughness, but
+                // nearly smooth metals reflect everything.
+                if roughness < 0.2 {
+                    assert!(albedo > 0.95, "{} {}", roughness, albedo);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn glossy_conserves_energy() {
+        let white = Color {
+            red: 1.0,
+            green: 1.0,
+            blue: 1.0,
+        };
+        for &roughness in [0.1, 0.5].iter() {
+            let material = Material::create_glossy(white, 1.5, roughness);
+            for &cos_theta in [1.0, 0.5, 0.1].iter() {
+                let albedo = mean_reflected(&material, cos_theta);
+                assert!(albedo < 1.01 && albedo > 0.5, "{} {}", roughness, albedo);
+            }
+        }
+    }
+
+    #[test]
+    fn fresnel_conductor_test() {
+        // Without absorption it is the dielectric reflectance.
+        assert!((fresnel_conductor(1.0, 1.5, 0.0) - 0.04).abs() < 1e-6);
+        // Everything is reflected at grazing angles.
+        assert!((fresnel_conductor(0.0, 0.2, 3.9) - 1.0).abs() < 1e-6);
+
+        let gold = Conductor::named("gold")
+            .unwrap()
+            .reflectance(1.0, &Channels::Rgb);
+        assert!(gold.red > 0.9 && gold.red > gold.green && gold.green > gold.blue);
+        assert!(Conductor::named("unobtainium").is_none());
+    }
+
+    #[test]
+    fn dispersion_test() {
+        // Schott N-BK7.
+        let bk7 = Dispersion::Sellmeier {
+            b: [1.039612, 0.2317923, 1.010469],
+            c: [0.006000699, 0.02001791, 103.5607],
+        };
+        assert!((bk7.ior(587.6) - 1.5168).abs() < 1e-4);
+        assert!(bk7.ior(450.0) > bk7.ior(650.0));
+        let cauchy = Dispersion::Cauchy { a: 1.5, b: 0.01 };
+        assert!((cauchy.ior(500.0) - 1.54).abs() < 1e-5);
+    }
 }
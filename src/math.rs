@@ -3,16 +3,128 @@ use std::ops::Mul;
 use std::ops::Neg;
 use std::ops::Sub;
 
+// Hits closer than this are ignored, so rays leaving a surface don't hit it
+// again due to rounding.
+const EPSILON: f32 = 1e-4;
+
 #[derive(Copy, Clone)]
 pub struct Intersection {
     pub point: Point,
+    // Geometric normal, facing the side the ray came from.
     pub normal: Vector,
+    // Normal used for shading, e.g. interpolated across a mesh or perturbed
+    // by a bump map. On the same side as `normal`.
+    pub shading_normal: Vector,
     pub distance: f32,
     pub inside: bool,
+    // Texture coordinates, along with the rates at which the point moves as
+    // they change. The derivatives span the tangent plane.
+    pub uv: [f32; 2],
+    pub dpdu: Vector,
+    pub dpdv: Vector,
+    // Hit point relative to the shape's own origin, for textures that should
+    // stay put on the object.
+    pub local: Vector,
 }
 
 pub trait Intersectable: Sync + Send {
     fn intersect(&self, ray: &Ray) -> Option<Intersection>;
+    // None for shapes that extend infinitely.
+    fn bounds(&self) -> Option<Aabb> {
+        None
+    }
+    /// Samples a direction from `from` towards the shape, for use as a light
+    /// source. Returns the direction and its solid angle density, or None if
+    /// the shape can't be sampled from there.
+    fn sample_towards(&self, _from: Point, _u1: f32, _u2: f32) -> Option<(Vector, f32)> {
+        None
+    }
+    /// Density with which `sample_towards` produces `direction`.
+    fn pdf_towards(&self, _from: Point, _direction: Vector) -> f32 {
+        0.0
+    }
+}
+
+/// Axis aligned bounding box.
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Point {
+                x: f32::INFINITY,
+                y: f32::INFINITY,
+                z: f32::INFINITY,
+            },
+            max: Point {
+                x: f32::NEG_INFINITY,
+                y: f32::NEG_INFINITY,
+                z: f32::NEG_INFINITY,
+            },
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb {
+            min: Point {
+                x: self.min.x.min(other.min.x),
+                y: self.min.y.min(other.min.y),
+                z: self.min.z.min(other.min.z),
+            },
+            max: Point {
+                x: self.max.x.max(other.max.x),
+                y: self.max.y.max(other.max.y),
+                z: self.max.z.max(other.max.z),
+            },
+        }
+    }
+
+    pub fn include(self, p: Point) -> Aabb {
+        self.union(Aabb { min: p, max: p })
+    }
+
+    pub fn centroid(self) -> Point {
+        translate(self.min, 0.5 * (self.max - self.min))
+    }
+
+    pub fn surface_area(self) -> f32 {
+        let d = self.max - self.min;
+        if d.x < 0.0 || d.y < 0.0 || d.z < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    /// Slab test, returns the distance where the ray enters the box if that
+    /// happens before `t_max`. `inv_direction` is the component-wise inverse of
+    /// the ray direction.
+    pub fn intersect(&self, ray: &Ray, inv_direction: Vector, t_max: f32) -> Option<f32> {
+        let mut t0 = 0.0_f32;
+        let mut t1 = t_max;
+        let axes = [
+            (self.min.x, self.max.x, ray.origin.x, inv_direction.x),
+            (self.min.y, self.max.y, ray.origin.y, inv_direction.y),
+            (self.min.z, self.max.z, ray.origin.z, inv_direction.z),
+        ];
+        for (min, max, origin, inv) in axes {
+            let mut near = (min - origin) * inv;
+            let mut far = (max - origin) * inv;
+            if near > far {
+                std::mem::swap(&mut near, &mut far);
+            }
+            // max/min rather than comparisons so NaNs from 0 * inf are ignored.
+            t0 = t0.max(near);
+            t1 = t1.min(far);
+            if t0 > t1 {
+                return None;
+            }
+        }
+        Some(t0)
+    }
 }
 
 pub struct Ray {
@@ -128,6 +240,26 @@ pub struct Plane {
     pub normal: Vector,
 }
 
+pub struct Triangle {
+    pub vertices: [Point; 3],
+    // Per-vertex shading normals, falls back to the face normal when absent.
+    pub normals: Option<[Vector; 3]>,
+    // Per-vertex texture coordinates, falls back to the barycentric
+    // coordinates when absent.
+    pub texcoords: Option<[[f32; 2]; 3]>,
+}
+
+impl Triangle {
+    /// Solid angle density of uniformly sampling the area, for a point `d2`
+    /// squared distance away along `direction`.
+    fn solid_angle_pdf(&self, direction: Vector, d2: f32) -> f32 {
+        let [p0, p1, p2] = self.vertices;
+        // The cross product is twice the area, in the normal's direction.
+        let projected_area = 0.5 * dot(cross(p1 - p0, p2 - p0), direction).abs();
+        d2 / projected_area
+    }
+}
+
 impl Plane {
     fn signed_distance(&self, point: Point) -> f32 {
         let pp = point - self.point;
@@ -155,6 +287,68 @@ pub fn cross(v1: Vector, v2: Vector) -> Vector {
     }
 }
 
+/// Moves a point on a surface slightly off it, to the side `direction` points
+/// to, so rays leaving from it don't hit the surface again.
+pub fn offset_origin(point: Point, normal: Vector, direction: Vector) -> Point {
+    if dot(direction, normal) >= 0.0 {
+        translate(point, EPSILON * normal)
+    } else {
+        translate(point, -EPSILON * normal)
+    }
+}
+
+/// Two unit vectors that together with the unit vector `n` form an
+/// orthonormal basis (Duff et al. 2017).
+pub fn orthonormal_basis(n: Vector) -> (Vector, Vector) {
+    let sign = 1.0_f32.copysign(n.z);
+    let a = -1.0 / (sign + n.z);
+    let b = n.x * n.y * a;
+    (
+        Vector {
+            x: 1.0 + sign * n.x * n.x * a,
+            y: sign * b,
+            z: -sign * n.x,
+        },
+        Vector {
+            x: b,
+            y: sign + n.y * n.y * a,
+            z: -n.y,
+        },
+    )
+}
+
+/// Orthonormal frame around a surface normal, local coordinates have the
+/// normal as z axis.
+#[derive(Copy, Clone)]
+pub struct Frame {
+    pub tangent: Vector,
+    pub bitangent: Vector,
+    pub normal: Vector,
+}
+
+impl Frame {
+    pub fn from_normal(normal: Vector) -> Frame {
+        let (tangent, bitangent) = orthonormal_basis(normal);
+        Frame {
+            tangent,
+            bitangent,
+            normal,
+        }
+    }
+
+    pub fn to_local(&self, v: Vector) -> Vector {
+        Vector {
+            x: dot(v, self.tangent),
+            y: dot(v, self.bitangent),
+            z: dot(v, self.normal),
+        }
+    }
+
+    pub fn to_world(&self, v: Vector) -> Vector {
+        v.x * self.tangent + v.y * self.bitangent + v.z * self.normal
+    }
+}
+
 impl Intersectable for Plane {
     fn intersect(&self, ray: &Ray) -> Option<Intersection> {
         let v = -dot(ray.direction, self.normal);
@@ -168,11 +362,20 @@ impl Intersectable for Plane {
             return None;
         }
         let t = d / v;
+        let point = translate(ray.origin, t * ray.direction);
+        // Distances along two directions in the plane.
+        let (u_axis, v_axis) = orthonormal_basis(self.normal);
+        let offset = point - self.point;
         Some(Intersection {
-            point: translate(ray.origin, t * ray.direction),
+            point,
             normal: self.normal,
+            shading_normal: self.normal,
             distance: t,
             inside: false,
+            uv: [dot(offset, u_axis), dot(offset, v_axis)],
+            dpdu: u_axis,
+            dpdv: v_axis,
+            local: offset,
         })
     }
 }
@@ -186,30 +389,248 @@ impl Intersectable for Sphere {
             return None;
         }
         let t1 = (-b - delta.sqrt()) / 2.0;
-        if t1 > 0.0 {
+        if t1 > EPSILON {
             let intersection = translate(ray.origin, t1 * ray.direction);
-            let normal = (intersection - self.center).normalize();
+            let local = intersection - self.center;
+            let normal = local.normalize();
+            let (dpdu, dpdv) = sphere_derivatives(local);
             return Some(Intersection {
                 point: intersection,
                 normal,
+                shading_normal: normal,
                 distance: t1,
                 inside: false,
+                uv: sphere_uv(normal),
+                dpdu,
+                dpdv,
+                local,
             });
         }
         let t2 = (-b + delta.sqrt()) / 2.0;
-        if t2 > 0.0 {
+        if t2 > EPSILON {
             let intersection = translate(ray.origin, t2 * ray.direction);
             // We are inside the sphere
-            let normal = (self.center - intersection).normalize();
+            let local = intersection - self.center;
+            let normal = -local.normalize();
+            let (dpdu, dpdv) = sphere_derivatives(local);
             return Some(Intersection {
                 point: intersection,
                 normal,
+                shading_normal: normal,
                 distance: t2,
                 inside: true,
+                uv: sphere_uv(-normal),
+                dpdu,
+                dpdv,
+                local,
             });
         }
         return None;
     }
+
+    fn sample_towards(&self, from: Point, u1: f32, u2: f32) -> Option<(Vector, f32)> {
+        // Uniformly sample the cone of directions the sphere subtends.
+        let to_center = self.center - from;
+        let d2 = to_center.square_length();
+        if d2 <= self.radius * self.radius {
+            return None;
+        }
+        let (_, one_minus_cos_max) = cone(self.radius * self.radius / d2);
+        let direction = sample_cone(to_center.normalize(), one_minus_cos_max, u1, u2);
+        Some((direction, cone_pdf(one_minus_cos_max)))
+    }
+
+    fn pdf_towards(&self, from: Point, direction: Vector) -> f32 {
+        let to_center = self.center - from;
+        let d2 = to_center.square_length();
+        if d2 <= self.radius * self.radius {
+            return 0.0;
+        }
+        let (cos_max, one_minus_cos_max) = cone(self.radius * self.radius / d2);
+        if dot(direction, to_center) < cos_max * d2.sqrt() {
+            return 0.0;
+        }
+        cone_pdf(one_minus_cos_max)
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let r = Vector {
+            x: self.radius,
+            y: self.radius,
+            z: self.radius,
+        };
+        Some(Aabb {
+            min: translate(self.center, -r),
+            max: translate(self.center, r),
+        })
+    }
+}
+
+/// Longitude and latitude of a point on the unit sphere, scaled to [0, 1] and
+/// with the poles on the z axis.
+fn sphere_uv(d: Vector) -> [f32; 2] {
+    [
+        0.5 + d.y.atan2(d.x) * (0.5 * std::f32::consts::FRAC_1_PI),
+        0.5 + d.z.clamp(-1.0, 1.0).asin() * std::f32::consts::FRAC_1_PI,
+    ]
+}
+
+/// Derivatives of the point on a sphere with respect to `sphere_uv`, given its
+/// offset from the center.
+fn sphere_derivatives(l: Vector) -> (Vector, Vector) {
+    use std::f32::consts::{PI, TAU};
+    // Distance from the polar axis, kept away from zero at the poles.
+    let rho = (l.x * l.x + l.y * l.y).sqrt().max(1e-6);
+    (
+        TAU * Vector {
+            x: -l.y,
+            y: l.x,
+            z: 0.0,
+        },
+        PI * Vector {
+            x: -l.x * l.z / rho,
+            y: -l.y * l.z / rho,
+            z: rho,
+        },
+    )
+}
+
+/// Cosine of the half angle of a cone with the given squared sine, along with
+/// one minus the cosine computed without cancellation for narrow cones.
+fn cone(sin2_max: f32) -> (f32, f32) {
+    let cos_max = (1.0 - sin2_max).sqrt();
+    (cos_max, sin2_max / (1.0 + cos_max))
+}
+
+/// Uniformly distributed direction within a cone around the unit vector
+/// `axis`, given one minus the cosine of its half angle.
+pub fn sample_cone(axis: Vector, one_minus_cos_max: f32, u1: f32, u2: f32) -> Vector {
+    let one_minus_cos = u1 * one_minus_cos_max;
+    // Rather than from the cosine, which loses the sine of narrow cones.
+    let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).max(0.0).sqrt();
+    let cos_theta = 1.0 - one_minus_cos;
+    let phi = 2.0 * std::f32::consts::PI * u2;
+    let (u, v) = orthonormal_basis(axis);
+    (sin_theta * phi.cos()) * u + (sin_theta * phi.sin()) * v + cos_theta * axis
+}
+
+/// Density of `sample_cone`.
+pub fn cone_pdf(one_minus_cos_max: f32) -> f32 {
+    1.0 / (2.0 * std::f32::consts::PI * one_minus_cos_max)
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<Intersection> {
+        // Möller–Trumbore
+        let [p0, p1, p2] = self.vertices;
+        let e1 = p1 - p0;
+        let e2 = p2 - p0;
+        let p = cross(ray.direction, e2);
+        let det = dot(e1, p);
+        // Relative to the size of the triangle, since the determinant grows
+        // with its area.
+        if det.abs() <= 1e-7 * (e1.square_length() * e2.square_length()).sqrt() {
+            // The ray is parallel to the triangle, or it has no area.
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = ray.origin - p0;
+        let u = dot(s, p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = cross(s, e1);
+        let v = dot(ray.direction, q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = dot(e2, q) * inv_det;
+        if t <= EPSILON {
+            return None;
+        }
+
+        // Seen from the back of the face we count as being inside the mesh.
+        let inside = det < 0.0;
+        let normal = cross(e1, e2).normalize();
+        let shading_normal = match self.normals {
+            Some([n0, n1, n2]) => ((1.0 - u - v) * n0 + u * n1 + v * n2).normalize(),
+            None => normal,
+        };
+        // Barycentric coordinates are the texture coordinates if there are no
+        // others, then the edges are the derivatives.
+        let (uv, dpdu, dpdv) = match self.texcoords {
+            Some([t0, t1, t2]) => {
+                let uv = [
+                    (1.0 - u - v) * t0[0] + u * t1[0] + v * t2[0],
+                    (1.0 - u - v) * t0[1] + u * t1[1] + v * t2[1],
+                ];
+                let (du1, dv1) = (t1[0] - t0[0], t1[1] - t0[1]);
+                let (du2, dv2) = (t2[0] - t0[0], t2[1] - t0[1]);
+                let uv_det = du1 * dv2 - dv1 * du2;
+                let uv_size = ((du1 * du1 + dv1 * dv1) * (du2 * du2 + dv2 * dv2)).sqrt();
+                if uv_det.abs() <= 1e-7 * uv_size {
+                    (uv, e1, e2)
+                } else {
+                    let inv = 1.0 / uv_det;
+                    (uv, inv * (dv2 * e1 - dv1 * e2), inv * (du1 * e2 - du2 * e1))
+                }
+            }
+            None => ([u, v], e1, e2),
+        };
+        let point = translate(ray.origin, t * ray.direction);
+        Some(Intersection {
+            point,
+            normal: if inside { -normal } else { normal },
+            shading_normal: if inside {
+                -shading_normal
+            } else {
+                shading_normal
+            },
+            distance: t,
+            inside,
+            uv,
+            dpdu,
+            dpdv,
+            // Meshes are modelled in place.
+            local: Vector {
+                x: point.x,
+                y: point.y,
+                z: point.z,
+            },
+        })
+    }
+
+    fn sample_towards(&self, from: Point, u1: f32, u2: f32) -> Option<(Vector, f32)> {
+        // Uniformly sample the area, then convert the density to solid angle.
+        let [p0, p1, p2] = self.vertices;
+        let s = u1.sqrt();
+        let target = translate(p0, (s * (1.0 - u2)) * (p1 - p0) + (s * u2) * (p2 - p0));
+        let to_target = target - from;
+        let d2 = to_target.square_length();
+        let direction = (1.0 / d2.sqrt()) * to_target;
+        let pdf = self.solid_angle_pdf(direction, d2);
+        // Seen edge on, or from a point on the triangle itself.
+        if !pdf.is_finite() || pdf <= 0.0 {
+            return None;
+        }
+        Some((direction, pdf))
+    }
+
+    fn pdf_towards(&self, from: Point, direction: Vector) -> f32 {
+        let ray = Ray {
+            origin: from,
+            direction,
+        };
+        match self.intersect(&ray) {
+            Some(i) => self.solid_angle_pdf(direction, i.distance * i.distance),
+            None => 0.0,
+        }
+    }
+
+    fn bounds(&self) -> Option<Aabb> {
+        let [p0, p1, p2] = self.vertices;
+        Some(Aabb::empty().include(p0).include(p1).include(p2))
+    }
 }
 
 #[cfg(test)]
@@ -715,4 +1136,447 @@ mod tests {
 
         assert!(res.is_none());
     }
+
+    fn unit_triangle() -> Triangle {
+        Triangle {
+            vertices: [
+                Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                Point {
+                    x: 1.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                Point {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+            ],
+            normals: None,
+            texcoords: None,
+        }
+    }
+
+    #[test]
+    fn triangle_ray_intersection_hit() {
+        let ray = Ray {
+            origin: Point {
+                x: 0.25,
+                y: 0.25,
+                z: 2.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+
+        let Intersection {
+            point: p,
+            normal,
+            distance: t,
+            inside,
+            ..
+        } = unit_triangle().intersect(&ray).unwrap();
+
+        assert_eq!(0.25, p.x);
+        assert_eq!(0.25, p.y);
+        assert_eq!(0.0, p.z);
+        assert_eq!(1.0, normal.z);
+        assert_eq!(2.0, t);
+        assert!(!inside);
+    }
+
+    #[test]
+    fn triangle_ray_intersection_back() {
+        let ray = Ray {
+            origin: Point {
+                x: 0.25,
+                y: 0.25,
+                z: -2.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        };
+
+        let Intersection { normal, inside, .. } = unit_triangle().intersect(&ray).unwrap();
+
+        assert_eq!(-1.0, normal.z);
+        assert!(inside);
+    }
+
+    #[test]
+    fn triangle_ray_intersection_miss() {
+        let ray = Ray {
+            origin: Point {
+                x: 0.75,
+                y: 0.75,
+                z: 2.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+
+        let res = unit_triangle().intersect(&ray);
+
+        assert!(res.is_none());
+    }
+
+    #[test]
+    fn triangle_size_independent() {
+        let down = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: -1.0,
+        };
+        let point = |x: f32, y: f32| Point { x, y, z: 0.0 };
+        let tiny = Triangle {
+            vertices: [point(0.0, 0.0), point(1e-7, 0.0), point(0.0, 1e-7)],
+            normals: None,
+            texcoords: None,
+        };
+        let ray = Ray {
+            origin: Point {
+                x: 2.5e-8,
+                y: 2.5e-8,
+                z: 1.0,
+            },
+            direction: down,
+        };
+        assert!(tiny.intersect(&ray).is_some());
+
+        let sliver = Triangle {
+            vertices: [point(0.0, 0.0), point(1.0, 0.0), point(1.0, 1e-9)],
+            normals: None,
+            texcoords: None,
+        };
+        let ray = Ray {
+            origin: Point {
+                x: 0.9,
+                y: 5e-10,
+                z: 1.0,
+            },
+            direction: down,
+        };
+        assert!(sliver.intersect(&ray).is_none());
+    }
+
+    #[test]
+    fn triangle_interpolated_normal() {
+        let up = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        let tilted = (Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 1.0,
+        })
+        .normalize();
+        let triangle = Triangle {
+            normals: Some([up, tilted, up]),
+            texcoords: None,
+            ..unit_triangle()
+        };
+        let ray = Ray {
+            origin: Point {
+                x: 1.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+
+        let Intersection {
+            normal,
+            shading_normal,
+            ..
+        } = triangle.intersect(&ray).unwrap();
+
+        assert!(almost_eq(tilted.x, shading_normal.x));
+        assert!(almost_eq(tilted.z, shading_normal.z));
+        // The geometric normal is the face's.
+        assert!(almost_eq(1.0, normal.z));
+    }
+
+    #[test]
+    fn triangle_interpolated_texcoords() {
+        let triangle = Triangle {
+            texcoords: Some([[0.0, 0.0], [0.5, 0.0], [0.0, 0.5]]),
+            ..unit_triangle()
+        };
+        let ray = Ray {
+            origin: Point {
+                x: 1.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+
+        let Intersection { uv, dpdu, dpdv, .. } = triangle.intersect(&ray).unwrap();
+
+        assert!(almost_eq(0.5, uv[0]));
+        assert!(almost_eq(0.0, uv[1]));
+        // Texture coordinates change at half the rate of the position.
+        assert!(almost_eq(2.0, dpdu.x));
+        assert!(almost_eq(2.0, dpdv.y));
+
+        // However small the texture coordinates are.
+        let tiny = Triangle {
+            texcoords: Some([[0.0, 0.0], [1e-7, 0.0], [0.0, 1e-7]]),
+            ..unit_triangle()
+        };
+        let Intersection { dpdu, .. } = tiny.intersect(&ray).unwrap();
+        assert!((dpdu.x / 1e7 - 1.0).abs() < 1e-4, "{}", dpdu.x);
+    }
+
+    #[test]
+    fn sphere_derivatives_match_uv() {
+        let l = Vector {
+            x: 0.6,
+            y: -1.2,
+            z: 1.5,
+        };
+        let r = l.length();
+        let (dpdu, dpdv) = sphere_derivatives(l);
+        let uv = sphere_uv(l.normalize());
+        let step = 1e-4;
+        let moved_u = sphere_uv((l + step * dpdu).normalize());
+        let moved_v = sphere_uv((l + step * dpdv).normalize());
+        assert!((moved_u[0] - uv[0] - step).abs() < 1e-5);
+        assert!((moved_u[1] - uv[1]).abs() < 1e-5);
+        assert!((moved_v[0] - uv[0]).abs() < 1e-5);
+        assert!((moved_v[1] - uv[1] - step).abs() < 1e-5);
+        // Both are tangent to the sphere.
+        assert!((dot(dpdu, l) / r).abs() < 1e-5);
+        assert!((dot(dpdv, l) / r).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sphere_uv_poles_and_seam() {
+        let uv = sphere_uv(Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        });
+        assert!(almost_eq(1.0, uv[1]));
+        let uv = sphere_uv(Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        });
+        assert!(almost_eq(0.5, uv[0]));
+        assert!(almost_eq(0.5, uv[1]));
+        let uv = sphere_uv(Vector {
+            x: -1.0,
+            y: -1e-6,
+            z: 0.0,
+        });
+        assert!(uv[0] < 1e-3);
+    }
+
+    #[test]
+    fn aabb_ray_intersection() {
+        let aabb = Aabb {
+            min: Point {
+                x: -1.0,
+                y: -1.0,
+                z: -1.0,
+            },
+            max: Point {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        };
+        let ray = Ray {
+            origin: Point {
+                x: -3.0,
+                y: 0.5,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        let inv_direction = Vector {
+            x: 1.0,
+            y: f32::INFINITY,
+            z: f32::INFINITY,
+        };
+
+        assert_eq!(
+            Some(2.0),
+            aabb.intersect(&ray, inv_direction, f32::INFINITY)
+        );
+        assert!(aabb.intersect(&ray, inv_direction, 1.0).is_none());
+    }
+
+    #[test]
+    fn aabb_ray_intersection_miss() {
+        let aabb = Aabb {
+            min: Point {
+                x: -1.0,
+                y: -1.0,
+                z: -1.0,
+            },
+            max: Point {
+                x: 1.0,
+                y: 1.0,
+                z: 1.0,
+            },
+        };
+        let ray = Ray {
+            origin: Point {
+                x: -3.0,
+                y: 2.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        };
+        let inv_direction = Vector {
+            x: 1.0,
+            y: f32::INFINITY,
+            z: f32::INFINITY,
+        };
+
+        assert!(aabb.intersect(&ray, inv_direction, f32::INFINITY).is_none());
+    }
+
+    #[test]
+    fn aabb_surface_area() {
+        let aabb = Aabb::empty()
+            .include(Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            })
+            .include(Point {
+                x: 1.0,
+                y: 2.0,
+                z: 3.0,
+            });
+        assert_eq!(22.0, aabb.surface_area());
+        assert_eq!(0.0, Aabb::empty().surface_area());
+    }
+
+    #[test]
+    fn orthonormal_basis_test() {
+        let n = (Vector {
+            x: 1.0,
+            y: -2.0,
+            z: 0.5,
+        })
+        .normalize();
+        let (u, v) = orthonormal_basis(n);
+        assert!(almost_eq(1.0, u.length()));
+        assert!(almost_eq(1.0, v.length()));
+        assert!(almost_eq(0.0, dot(u, n)));
+        assert!(almost_eq(0.0, dot(v, n)));
+        assert!(almost_eq(0.0, dot(u, v)));
+    }
+
+    #[test]
+    fn sphere_sample_towards_hits_sphere() {
+        let sphere = Sphere {
+            center: Point {
+                x: 0.0,
+                y: 5.0,
+                z: 0.0,
+            },
+            radius: 1.0,
+        };
+        let from = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        for &(u1, u2) in [(0.0, 0.0), (0.5, 0.25), (0.999, 0.75)].iter() {
+            let (direction, pdf) = sphere.sample_towards(from, u1, u2).unwrap();
+            let ray = Ray {
+                origin: from,
+                direction,
+            };
+            assert!(sphere.intersect(&ray).is_some());
+            assert_eq!(pdf, sphere.pdf_towards(from, direction));
+        }
+    }
+
+    #[test]
+    fn triangle_sample_towards_hits_triangle() {
+        let point = |x: f32, z: f32| Point { x, y: 5.0, z };
+        let triangle = Triangle {
+            vertices: [point(0.0, 0.0), point(0.1, 0.0), point(0.0, 0.1)],
+            normals: None,
+            texcoords: None,
+        };
+        let from = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        for &(u1, u2) in [(0.0, 0.0), (0.5, 0.25), (0.999, 0.75)].iter() {
+            let (direction, pdf) = triangle.sample_towards(from, u1, u2).unwrap();
+            let ray = Ray {
+                origin: from,
+                direction,
+            };
+            assert!(triangle.intersect(&ray).is_some());
+            let expected = triangle.pdf_towards(from, direction);
+            assert!((pdf - expected).abs() < 1e-3 * pdf);
+            // Small and far away, so about the distance squared over the area.
+            assert!((pdf - 5000.0).abs() < 0.01 * pdf);
+        }
+        let edge_on = Point {
+            x: -1.0,
+            y: 5.0,
+            z: 0.0,
+        };
+        assert!(triangle.sample_towards(edge_on, 0.5, 0.5).is_none());
+    }
+
+    #[test]
+    fn sphere_sample_towards_inside() {
+        let sphere = Sphere {
+            center: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            radius: 1.0,
+        };
+        let from = Point {
+            x: 0.0,
+            y: 0.5,
+            z: 0.0,
+        };
+        assert!(sphere.sample_towards(from, 0.5, 0.5).is_none());
+    }
 }
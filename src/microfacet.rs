@@ -0,0 +1,181 @@
+//! GGX (Trowbridge-Reitz) microfacet distribution with the height-correlated
+//! Smith masking-shadowing function. Directions are in the local frame of the
+//! surface, z being the macroscopic normal, and point away from the surface.
+
+use crate::math::*;
+
+/// Below this roughness surfaces are treated as perfectly smooth, the
+/// distribution gets too peaked to evaluate in single precision.
+pub const MIN_ROUGHNESS: f32 = 1e-3;
+
+/// Width of the distribution for a perceptually linear roughness in [0, 1].
+pub fn alpha(roughness: f32) -> f32 {
+    roughness * roughness
+}
+
+/// Density of microfacet normals `h`, per unit projected area.
+pub fn distribution(alpha: f32, h: Vector) -> f32 {
+    if h.z <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = h.z * h.z * (a2 - 1.0) + 1.0;
+    a2 / (std::f32::consts::PI * d * d)
+}
+
+fn lambda(alpha: f32, v: Vector) -> f32 {
+    let cos2 = v.z * v.z;
+    let tan2 = (1.0 - cos2).max(0.0) / cos2;
+    0.5 * ((1.0 + alpha * alpha * tan2).sqrt() - 1.0)
+}
+
+/// Fraction of the microfacets facing `v` that are visible from it.
+pub fn smith_g1(alpha: f32, v: Vector) -> f32 {
+    if v.z <= 0.0 {
+        return 0.0;
+    }
+    1.0 / (1.0 + lambda(alpha, v))
+}
+
+/// Fraction of microfacets visible from both `i` and `o`.
+pub fn smith_g2(alpha: f32, i: Vector, o: Vector) -> f32 {
+    if i.z <= 0.0 || o.z <= 0.0 {
+        return 0.0;
+    }
+    1.0 / (1.0 + lambda(alpha, i) + lambda(alpha, o))
+}
+
+/// Samples a microfacet normal visible from `v` (Heitz 2018), distributed
+/// proportional to `smith_g1(v) * max(0, dot(v, h)) * distribution(h) / v.z`.
+pub fn sample_visible_normal(alpha: f32, v: Vector, u1: f32, u2: f32) -> Vector {
+    // Stretch the view direction to sample the hemisphere configuration.
+    let vh = Vector {
+        x: alpha * v.x,
+        y: alpha * v.y,
+        z: v.z,
+    }
+    .normalize();
+    let len2 = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len2 > 0.0 {
+        (1.0 / len2.sqrt())
+            * Vector {
+                x: -vh.y,
+                y: vh.x,
+                z: 0.0,
+            }
+    } else {
+        Vector {
+            x: 1.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    };
+    let t2 = cross(vh, t1);
+
+    // Uniform point on the projected disk, warped to the visible half.
+    let r = u1.sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u2;
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+    // Unstretch.
+    Vector {
+        x: alpha * nh.x,
+        y: alpha * nh.y,
+        z: nh.z.max(0.0),
+    }
+    .normalize()
+}
+
+/// Solid angle density of reflecting `i` into `o` off a normal sampled with
+/// `sample_visible_normal`.
+pub fn reflection_pdf(alpha: f32, i: Vector, o: Vector) -> f32 {
+    if i.z <= 0.0 || o.z <= 0.0 {
+        return 0.0;
+    }
+    let h = (i + o).normalize();
+    smith_g1(alpha, i) * distribution(alpha, h) / (4.0 * i.z)
+}
+
+/// Reflects `v` about the microfacet normal `h`.
+pub fn reflect(v: Vector, h: Vector) -> Vector {
+    2.0 * dot(v, h) * h - v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_xorshift::XorShiftRng;
+
+    fn direction(theta: f32) -> Vector {
+        Vector {
+            x: theta.sin(),
+            y: 0.0,
+            z: theta.cos(),
+        }
+    }
+
+    #[test]
+    fn projected_area_is_one() {
+        // The projected microfacet area equals the macro surface area.
+        for &alpha in [0.1, 0.5, 1.0].iter() {
+            let steps = 2000;
+            let mut sum = 0.0;
+            for k in 0..steps {
+                let theta = (k as f32 + 0.5) / steps as f32 * std::f32::consts::FRAC_PI_2;
+                let h = direction(theta);
+                // Integrated over phi, which the distribution doesn't depend on.
+                sum += distribution(alpha, h)
+                    * h.z
+                    * theta.sin()
+                    * 2.0
+                    * std::f32::consts::PI
+                    * std::f32::consts::FRAC_PI_2
+                    / steps as f32;
+            }
+            assert!((sum - 1.0).abs() < 0.01, "alpha {}: {}", alpha, sum);
+        }
+    }
+
+    #[test]
+    fn visible_normals_match_pdf() {
+        // The mean of f / pdf over samples estimates the integral of f, for
+        // f = 1 over the hemisphere it is 2 pi.
+        let mut rng = XorShiftRng::seed_from_u64(1);
+        let alpha = 0.4;
+        let i = direction(0.7);
+        let n = 200000;
+        let mut sum = 0.0;
+        let mut reflected = 0;
+        for _ in 0..n {
+            let h = sample_visible_normal(alpha, i, rng.gen(), rng.gen());
+            assert!(h.z >= 0.0 && dot(h, i) >= -1e-5);
+            let o = reflect(i, h);
+            if o.z <= 0.0 {
+                continue;
+            }
+            reflected += 1;
+            sum += 1.0 / reflection_pdf(alpha, i, o) as f64;
+        }
+        // Samples reflected below the horizon carry no density above it.
+        let estimate = sum / n as f64;
+        let expected = 2.0 * std::f64::consts::PI;
+        assert!(reflected > n / 2);
+        assert!(
+            (estimate - expected).abs() / expected < 0.03,
+            "{}",
+            estimate
+        );
+    }
+
+    #[test]
+    fn smith_limits() {
+        let up = direction(0.0);
+        assert_eq!(1.0, smith_g1(0.5, up));
+        assert_eq!(0.0, smith_g1(0.5, direction(2.0)));
+        assert!(smith_g2(0.5, direction(1.2), direction(1.2)) < smith_g1(0.5, direction(1.2)));
+    }
+}
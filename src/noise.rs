@@ -0,0 +1,164 @@
+//! Gradient noise for procedural textures, following Perlin's improved noise
+//! (2002).
+
+use std::sync::OnceLock;
+
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_xorshift::XorShiftRng;
+
+use crate::math::Vector;
+
+/// Shuffled 0..256, repeated so lookups of sums of two entries don't need to
+/// wrap. Fixed so textures look the same in every render.
+fn permutation() -> &'static [usize; 512] {
+    static PERMUTATION: OnceLock<[usize; 512]> = OnceLock::new();
+    PERMUTATION.get_or_init(|| {
+        let mut p: Vec<usize> = (0..256).collect();
+        p.shuffle(&mut XorShiftRng::seed_from_u64(0));
+        let mut res = [0; 512];
+        for (i, r) in res.iter_mut().enumerate() {
+            *r = p[i % 256];
+        }
+        res
+    })
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+/// Dot product of the offset with one of twelve edge directions of a cube.
+fn grad(hash: usize, x: f32, y: f32, z: f32) -> f32 {
+    let h = hash & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 {
+        y
+    } else if h == 12 || h == 14 {
+        x
+    } else {
+        z
+    };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/// Smooth noise of roughly unit frequency, about in [-1, 1] and zero on
+/// integer lattice points.
+pub fn noise(p: Vector) -> f32 {
+    let perm = permutation();
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (x, y, z) = (p.x - fx, p.y - fy, p.z - fz);
+    let xi = (fx as i64).rem_euclid(256) as usize;
+    let yi = (fy as i64).rem_euclid(256) as usize;
+    let zi = (fz as i64).rem_euclid(256) as usize;
+    let (u, v, w) = (fade(x), fade(y), fade(z));
+
+    let a = perm[xi] + yi;
+    let aa = perm[a] + zi;
+    let ab = perm[a + 1] + zi;
+    let b = perm[xi + 1] + yi;
+    let ba = perm[b] + zi;
+    let bb = perm[b + 1] + zi;
+
+    lerp(
+        w,
+        lerp(
+            v,
+            lerp(u, grad(perm[aa], x, y, z), grad(perm[ba], x - 1.0, y, z)),
+            lerp(
+                u,
+                grad(perm[ab], x, y - 1.0, z),
+                grad(perm[bb], x - 1.0, y - 1.0, z),
+            ),
+        ),
+        lerp(
+            v,
+            lerp(
+                u,
+                grad(perm[aa + 1], x, y, z - 1.0),
+                grad(perm[ba + 1], x - 1.0, y, z - 1.0),
+            ),
+            lerp(
+                u,
+                grad(perm[ab + 1], x, y - 1.0, z - 1.0),
+                grad(perm[bb + 1], x - 1.0, y - 1.0, z - 1.0),
+            ),
+        ),
+    )
+}
+
+/// Fractional Brownian motion: octaves of noise at doubling frequencies and
+/// halving amplitudes.
+pub fn fbm(p: Vector, octaves: u32) -> f32 {
+    octaves_sum(p, octaves, noise)
+}
+
+/// Like `fbm` summing the absolute value of each octave, which gives creases
+/// where the noise crosses zero.
+pub fn turbulence(p: Vector, octaves: u32) -> f32 {
+    octaves_sum(p, octaves, |p| noise(p).abs())
+}
+
+fn octaves_sum(p: Vector, octaves: u32, f: impl Fn(Vector) -> f32) -> f32 {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+    for _ in 0..octaves {
+        sum += amplitude * f(frequency * p);
+        frequency *= 2.0;
+        amplitude *= 0.5;
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn zero_on_lattice() {
+        for &(x, y, z) in [(0.0, 0.0, 0.0), (3.0, -2.0, 7.0), (-300.0, 1.0, 0.0)].iter() {
+            assert_eq!(0.0, noise(Vector { x, y, z }));
+        }
+    }
+
+    #[test]
+    fn bounded_and_varied() {
+        let mut rng = XorShiftRng::seed_from_u64(5);
+        let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+        for _ in 0..10000 {
+            let p = Vector {
+                x: rng.gen_range(-50.0..50.0),
+                y: rng.gen_range(-50.0..50.0),
+                z: rng.gen_range(-50.0..50.0),
+            };
+            let n = noise(p);
+            min = min.min(n);
+            max = max.max(n);
+            assert!(turbulence(p, 4) >= 0.0);
+            assert!(fbm(p, 4).abs() < 2.0);
+        }
+        assert!(min > -1.1 && min < -0.5, "{}", min);
+        assert!(max < 1.1 && max > 0.5, "{}", max);
+    }
+
+    #[test]
+    fn continuous() {
+        let p = Vector {
+            x: 1.3,
+            y: 2.7,
+            z: -0.4,
+        };
+        let q = Vector {
+            x: 1.3001,
+            y: 2.7,
+            z: -0.4,
+        };
+        assert!((noise(p) - noise(q)).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::material;
+use crate::math::*;
+use crate::scene;
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Parse {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path.display(), line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Loads a Wavefront OBJ file as one object per triangle. Materials come from
+/// the `.mtl` libraries referenced by the file unless `material` overrides them.
+pub fn load(
+    path: &Path,
+    material: Option<&material::Material>,
+) -> Result<Vec<scene::Object>, Error> {
+    let source = read(path)?;
+    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    parse(&source, path, material, |name| {
+        let mtl_path = dir.join(name);
+        parse_mtl(&read(&mtl_path)?, &mtl_path)
+    })
+}
+
+fn read(path: &Path) -> Result<String, Error> {
+    fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+fn parse<F>(
+    source: &str,
+    path: &Path,
+    material: Option<&material::Material>,
+    mut load_library: F,
+) -> Result<Vec<scene::Object>, Error>
+where
+    F: FnMut(&str) -> Result<HashMap<String, material::Material>, Error>,
+{
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut texcoords = Vec::new();
+    let mut library = HashMap::new();
+    let mut current = default_material();
+    let mut objs = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let error = |message: String| Error::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message,
+        };
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = words.collect();
+        match keyword {
+            "v" => {
+                let [x, y, z] = floats(&args).map_err(error)?;
+                positions.push(Point { x, y, z });
+            }
+            "vn" => {
+                let [x, y, z] = floats(&args).map_err(error)?;
+                let normal = Vector { x, y, z };
+                let length = normal.square_length();
+                if length == 0.0 || !length.is_finite() {
+                    return Err(error("a normal must not be zero".to_string()));
+                }
+                normals.push(normal.normalize());
+            }
+            "vt" => {
+                // The optional third coordinate is for 3D textures.
+                if args.is_empty() {
+                    return Err(error("expected texture coordinates".to_string()));
+                }
+                let mut uv = [0.0; 2];
+                for (i, a) in args.iter().enumerate() {
+                    let value = a
+                        .parse::<f32>()
+                        .map_err(|_| error(format!("invalid number `{}`", a)))?;
+                    if i < 2 {
+                        uv[i] = value;
+                    }
+                }
+                texcoords.push(uv);
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err(error("a face needs at least three vertices".to_string()));
+                }
+                let mut corners = Vec::with_capacity(args.len());
+                for a in args.iter() {
+                    corners.push(
+                        face_vertex(a, positions.len(), texcoords.len(), normals.len())
+                            .map_err(error)?,
+                    );
+                }
+                // Triangulate polygons as a fan around the first vertex.
+                for k in 1..corners.len() - 1 {
+                    let c = [corners[0], corners[k], corners[k + 1]];
+                    let face_texcoords = match (c[0].1, c[1].1, c[2].1) {
+                        (Some(t0), Some(t1), Some(t2)) => {
+                            Some([texcoords[t0], texcoords[t1], texcoords[t2]])
+                        }
+                        _ => None,
+                    };
+                    let face_normals = match (c[0].2, c[1].2, c[2].2) {
+                        (Some(n0), Some(n1), Some(n2)) => {
+                            Some([normals[n0], normals[n1], normals[n2]])
+                        }
+                        _ => None,
+                    };
+                    objs.push(scene::Object {
+                        shape: Box::new(Triangle {
+                            vertices: [positions[c[0].0], positions[c[1].0], positions[c[2].0]],
+                            normals: face_normals,
+                            texcoords: face_texcoords,
+                        }),
+                        material: match material {
+                            Some(m) => m.clone(),
+                            None => current.clone(),
+                        },
+                    });
+                }
+            }
+            "mtllib" => {
+                library.extend(load_library(rest_of_line(line, keyword))?);
+            }
+            "usemtl" => {
+                let name = rest_of_line(line, keyword);
+                current = match library.get(name) {
+                    Some(m) => m.clone(),
+                    None if material.is_some() => current,
+                    None => return Err(error(format!("unknown material `{}`", name))),
+                };
+            }
+            // Groups, smoothing groups and the like don't affect rendering.
+            _ => {}
+        }
+    }
+    Ok(objs)
+}
+
+fn parse_mtl(source: &str, path: &Path) -> Result<HashMap<String, material::Material>, Error> {
+    struct Entry {
+        diffuse: material::Color,
+        emissive: material::Color,
+        ior: f32,
+        transparency: f32,
+    }
+    fn build(e: &Entry) -> material::Material {
+        if e.emissive.red != 0.0 || e.emissive.green != 0.0 || e.emissive.blue != 0.0 {
+            material::Material::create_emissive(e.emissive)
+        } else {
+            material::Material::create(e.diffuse, e.ior, e.transparency)
+        }
+    }
+
+    let mut materials = HashMap::new();
+    let mut current: Option<(String, Entry)> = None;
+    for (i, line) in source.lines().enumerate() {
+        let error = |message: String| Error::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message,
+        };
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(k) if !k.starts_with('#') => k,
+            _ => continue,
+        };
+        let args: Vec<&str> = words.collect();
+        if keyword == "newmtl" {
+            if let Some((name, entry)) = current.take() {
+                materials.insert(name, build(&entry));
+            }
+            let black = material::Color {
+                red: 0.0,
+                green: 0.0,
+                blue: 0.0,
+            };
+            current = Some((
+                rest_of_line(line, keyword).to_string(),
+                Entry {
+                    diffuse: black,
+                    emissive: black,
+                    ior: 1.0,
+                    transparency: 0.0,
+                },
+            ));
+            continue;
+        }
+        let entry = match current.as_mut() {
+            Some((_, entry)) => entry,
+            None => continue,
+        };
+        match keyword {
+            "Kd" => entry.diffuse = color(&args).map_err(error)?,
+            "Ke" => entry.emissive = color(&args).map_err(error)?,
+            "Ni" => entry.ior = float(&args).map_err(error)?,
+            "d" => entry.transparency = 1.0 - float(&args).map_err(error)?,
+            "Tr" => entry.transparency = float(&args).map_err(error)?,
+            _ => {}
+        }
+    }
+    if let Some((name, entry)) = current {
+        materials.insert(name, build(&entry));
+    }
+    Ok(materials)
+}
+
+/// Everything after `keyword`, so names may contain spaces. Only one material
+/// library can be named per `mtllib` line.
+fn rest_of_line<'a>(line: &'a str, keyword: &str) -> &'a str {
+    line.trim_start()[keyword.len()..].trim()
+}
+
+fn default_material() -> material::Material {
+    material::Material::create(
+        material::Color {
+            red: 0.8,
+            green: 0.8,
+            blue: 0.8,
+        },
+        1.0,
+        0.0,
+    )
+}
+
+/// Parses one `v`, `v/vt`, `v//vn` or `v/vt/vn` face corner into zero-based
+/// position, texture coordinate and normal indices.
+fn face_vertex(
+    word: &str,
+    positions: usize,
+    texcoords: usize,
+    normals: usize,
+) -> Result<(usize, Option<usize>, Option<usize>), String> {
+    let mut parts = word.split('/');
+    let position = index(parts.next().unwrap_or(""), positions)?;
+    let texcoord = match parts.next() {
+        Some(t) if !t.is_empty() => Some(index(t, texcoords)?),
+        _ => None,
+    };
+    let normal = match parts.next() {
+        Some(n) if !n.is_empty() => Some(index(n, normals)?),
+        _ => None,
+    };
+    Ok((position, texcoord, normal))
+}
+
+/// OBJ indices are one-based, negative ones count back from the latest element.
+fn index(word: &str, count: usize) -> Result<usize, String> {
+    let i: i64 = word
+        .parse()
+        .map_err(|_| format!("invalid index `{}`", word))?;
+    let resolved = if i < 0 { count as i64 + i } else { i - 1 };
+    if i == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(format!("index {} out of range", i));
+    }
+    Ok(resolved as usize)
+}
+
+fn float(args: &[&str]) -> Result<f32, String> {
+    match args.first() {
+        Some(a) => a.parse().map_err(|_| format!("invalid number `{}`", a)),
+        None => Err("expected a number".to_string()),
+    }
+}
+
+fn floats(args: &[&str]) -> Result<[f32; 3], String> {
+    if args.len() < 3 {
+        return Err(format!("expected 3 numbers, found {}", args.len()));
+    }
+    let mut res = [0.0; 3];
+    for (r, a) in res.iter_mut().zip(args.iter()) {
+        *r = a.parse().map_err(|_| format!("invalid number `{}`", a))?;
+    }
+    Ok(res)
+}
+
+fn color(args: &[&str]) -> Result<material::Color, String> {
+    let [red, green, blue] = floats(args)?;
+    Ok(material::Color { red, green, blue })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "
+mtllib quad.mtl
+v 0 0 0
+v 1 0 0
+v 1 1 0
+v 0 1 0
+vt 0 0
+vn 0 0 1
+usemtl red
+f 1/1/1 2/1/1 3/1/1 4/1/1
+f -4 -3 -2
+";
+
+    const MTL: &str = "
+newmtl red
+Kd 1 0 0
+Ni 1.5
+";
+
+    fn parse_str(source: &str) -> Result<Vec<scene::Object>, Error> {
+        parse(source, Path::new("test.obj"), None, |name| {
+            assert_eq!("quad.mtl", name);
+            parse_mtl(MTL, Path::new(name))
+        })
+    }
+
+    #[test]
+    fn parse_quad() {
+        let objs = parse_str(QUAD).unwrap();
+        // The quad is split in two, plus the extra triangle.
+        assert_eq!(3, objs.len());
+    }
+
+    #[test]
+    fn parse_quad_intersect() {
+        let objs = parse_str(QUAD).unwrap();
+        let ray = Ray {
+            origin: Point {
+                x: 0.75,
+                y: 0.5,
+                z: 1.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+        let hits = objs
+            .iter()
+            .filter(|o| o.shape.intersect(&ray).is_some())
+            .count();
+        assert_eq!(2, hits);
+    }
+
+    #[test]
+    fn parse_texcoords() {
+        let source = "v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 2 0 0\nvt 0 2\nf 1/1 2/2 3/3\n";
+        let objs = parse_str(source).unwrap();
+        let ray = Ray {
+            origin: Point {
+                x: 0.5,
+                y: 0.25,
+                z: 1.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: -1.0,
+            },
+        };
+        let hit = objs[0].shape.intersect(&ray).unwrap();
+        assert!((hit.uv[0] - 1.0).abs() < 1e-5);
+        assert!((hit.uv[1] - 0.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn parse_error_reports_line() {
+        let source = "v 0 0 0\nv 1 0 0\nf 1 2 3\n";
+        match parse_str(source) {
+            Err(Error::Parse { line, .. }) => assert_eq!(3, line),
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_zero_normal() {
+        let source = "v 0 0 0\nvn 0 0 0\n";
+        match parse_str(source) {
+            Err(Error::Parse { line, .. }) => assert_eq!(2, line),
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_names_with_spaces() {
+        let source = "mtllib my  quad.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl dark  red\nf 1 2 3\n";
+        let objs = parse(source, Path::new("test.obj"), None, |name| {
+            assert_eq!("my  quad.mtl", name);
+            parse_mtl("newmtl dark  red\nKd 1 0 0\n", Path::new(name))
+        })
+        .unwrap();
+        assert_eq!(1, objs.len());
+    }
+
+    #[test]
+    fn parse_unknown_material() {
+        let source = "v 0 0 0\nusemtl missing\n";
+        assert!(parse_str(source).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageError, ImageFormat, Rgb};
+
+use crate::film::{Film, ToneMapping};
+
+/// Saves the film, picking the format from the file extension. OpenEXR
+/// (`.exr`), Radiance (`.hdr`) and PFM (`.pfm`) files get the linear radiance,
+/// anything else is tone mapped to 8-bit sRGB, without alpha since formats
+/// like JPEG can't store it.
+pub fn save(path: &Path, film: &Film, tone_mapping: &ToneMapping) -> Result<(), ImageError> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("exr") => {
+            let buffer: image::Rgb32FImage =
+                image::ImageBuffer::from_raw(film.width as u32, film.height as u32, linear(film))
+                    .unwrap();
+            buffer.save_with_format(path, ImageFormat::OpenExr)
+        }
+        Some("hdr") => {
+            let pixels: Vec<Rgb<f32>> = linear(film)
+                .chunks(3)
+                .map(|c| Rgb([c[0], c[1], c[2]]))
+                .collect();
+            let writer = BufWriter::new(File::create(path)?);
+            HdrEncoder::new(writer).encode(&pixels, film.width, film.height)
+        }
+        Some("pfm") => {
+            write_pfm(&mut BufWriter::new(File::create(path)?), film)?;
+            Ok(())
+        }
+        _ => {
+            let rgb: Vec<u8> = film
+                .to_rgba8(tone_mapping)
+                .chunks(4)
+                .flat_map(|p| p[..3].iter().copied())
+                .collect();
+            image::save_buffer(
+                path,
+                &rgb,
+                film.width as u32,
+                film.height as u32,
+                image::ColorType::Rgb8,
+            )
+        }
+    }
+}
+
+/// Saves a greyscale image of how many samples each pixel got, white being
+/// the most any pixel got. Shows where adaptive sampling spent its time.
+pub fn save_sample_counts(path: &Path, film: &Film) -> Result<(), ImageError> {
+    image::save_buffer(
+        path,
+        &sample_counts(film),
+        film.width as u32,
+        film.height as u32,
+        image::ColorType::L8,
+    )
+}
+
+fn sample_counts(film: &Film) -> Vec<u8> {
+    let counts: Vec<u32> = (0..film.height)
+        .flat_map(|y| (0..film.width).map(move |x| (x, y)))
+        .map(|(x, y)| film.samples(x, y))
+        .collect();
+    let max = counts.iter().copied().max().unwrap_or(0).max(1);
+    counts
+        .iter()
+        .map(|&n| (255.0 * n as f32 / max as f32).round() as u8)
+        .collect()
+}
+
+/// Mean radiance of every pixel as interleaved RGB, top row first.
+fn linear(film: &Film) -> Vec<f32> {
+    let mut data = Vec::with_capacity(film.width * film.height * 3);
+    for y in 0..film.height {
+        for x in 0..film.width {
+            let val = film.pixel(x, y);
+            data.extend_from_slice(&[val.red, val.green, val.blue]);
+        }
+    }
+    data
+}
+
+fn write_pfm<W: Write>(w: &mut W, film: &Film) -> io::Result<()> {
+    // A negative scale marks the data as little endian.
+    write!(w, "PF\n{} {}\n-1.0\n", film.width, film.height)?;
+    // Rows are stored bottom to top.
+    for y in (0..film.height).rev() {
+        for x in 0..film.width {
+            let val = film.pixel(x, y);
+            for c in [val.red, val.green, val.blue] {
+                w.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Color;
+
+    #[test]
+    fn pfm_layout() {
+        let mut film = Film::new(1, 2);
+        film.add_sample(
+            0,
+            0,
+            Color {
+                red: 1.0,
+                green: 2.0,
+                blue: 3.0,
+            },
+        );
+        film.add_sample(
+            0,
+            1,
+            Color {
+                red: 4.0,
+                green: 5.0,
+                blue: 6.0,
+            },
+        );
+        let mut data = Vec::new();
+        write_pfm(&mut data, &film).unwrap();
+
+        let header = b"PF\n1 2\n-1.0\n";
+        assert_eq!(&header[..], &data[..header.len()]);
+        let floats: Vec<f32> = data[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        // Bottom row first.
+        assert_eq!(vec![4.0, 5.0, 6.0, 1.0, 2.0, 3.0], floats);
+    }
+
+    #[test]
+    fn sample_counts_scale_to_most() {
+        let mut film = Film::new(3, 1);
+        let grey = Color {
+            red: 0.5,
+            green: 0.5,
+            blue: 0.5,
+        };
+        for (x, n) in [(0, 4), (1, 1)] {
+            for _ in 0..n {
+                film.add_sample(x, 0, grey);
+            }
+        }
+        assert_eq!(vec![255, 64, 0], sample_counts(&film));
+    }
+}
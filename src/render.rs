@@ -1,140 +1,338 @@
-extern crate rand_xorshift;
-use rand::prelude::*;
-use rand_xorshift::XorShiftRng;
-
+use std::collections::BTreeMap;
 use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
 
+use crate::checkpoint::{Autosave, Checkpoint};
+use crate::film;
+use crate::light;
 use crate::material;
 use crate::math::*;
 use crate::preview;
+use crate::sampler::{Sampler, SamplerType};
 use crate::scene;
+use crate::spectrum::{self, Channels};
+
+// Samples per pixel taken by each job.
+const SAMPLES_PER_JOB: u32 = 10;
+const TILE_SIZE: usize = 32;
+// Don't redraw the preview for every finished tile.
+const PREVIEW_INTERVAL: Duration = Duration::from_millis(100);
+// Passes every pixel gets before adaptive sampling goes by its noise or
+// time and quality limits are checked.
+const ADAPTIVE_FIRST_PASSES: u32 = 2;
+// Sampler dimensions used by the camera: two for the position within the
+// pixel, two on the lens and one for the wavelengths.
+const CAMERA_DIMENSIONS: u32 = 5;
+// Dimensions every bounce gets, whatever it uses of them: up to four for
+// the BSDF, three for direct lighting and one for Russian roulette.
+const BOUNCE_DIMENSIONS: u32 = 8;
+const LIGHT_DIMENSION: u32 = 4;
+const ROULETTE_DIMENSION: u32 = 7;
 
-const THREADS: i64 = 4;
+/// How many bounces paths take.
+#[derive(Copy, Clone)]
+pub struct Depth {
+    // Bounces before Russian roulette starts ending paths carrying little
+    // light.
+    pub min: u32,
+    // Paths end after this many bounces whatever they carry, still picking
+    // up the light the last one finds.
+    pub max: u32,
+}
+
+impl Default for Depth {
+    fn default() -> Depth {
+        Depth { min: 3, max: 100 }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Settings {
+    pub width: usize,
+    pub height: usize,
+    pub samples_per_pixel: u32,
+    pub threads: usize,
+    // Every job derives its random numbers from this, so the same seed gives
+    // the same image.
+    pub seed: u64,
+    // Trace single wavelengths instead of RGB, for dispersion.
+    pub spectral: bool,
+    pub depth: Depth,
+    pub sampler: SamplerType,
+    // Pixels whose error (see `film::Film::error`) drops below this
+    // get no more samples.
+    pub noise_threshold: Option<f32>,
+    // Stop once rendering has taken this long.
+    pub time_limit: Option<Duration>,
+    // Stop once `film::Film::mean_relative_error` drops below this.
+    pub target_error: Option<f32>,
+    // Used for the preview.
+    pub tone_mapping: film::ToneMapping,
+}
+
+// One pass over one tile.
+struct Job {
+    index: usize,
+    tile: film::Tile,
+    // Index of the first pixel sample, the rest follow.
+    first_sample: u32,
+    samples: u32,
+    // Pixels of the tile to sample, row by row, all if not given.
+    active: Option<Vec<bool>>,
+}
+
+struct TileResult {
+    job: Job,
+    film: film::Film,
+}
 
 fn start_render_job(
     pool: &threadpool::ThreadPool,
     scene: &Arc<scene::Scene>,
     camera: &Arc<scene::Camera>,
-    tx: &mpsc::Sender<Vec<material::Color>>,
-    width: usize,
-    height: usize,
-    rays_per_pixel: i64,
+    tx: &mpsc::Sender<TileResult>,
+    stop: &Arc<AtomicBool>,
+    settings: &Settings,
+    job: Job,
 ) {
-    let my_scene = Arc::clone(&scene);
-    let my_camera = Arc::clone(&camera);
-    let my_tx = mpsc::Sender::clone(&tx);
+    let width = settings.width;
+    let height = settings.height;
+    let seed = settings.seed;
+    let spectral = settings.spectral;
+    let depth = settings.depth;
+    let sampler_type = settings.sampler;
+    let samples_per_pixel = settings.samples_per_pixel;
+    let my_scene = Arc::clone(scene);
+    let my_camera = Arc::clone(camera);
+    let my_tx = mpsc::Sender::clone(tx);
+    let my_stop = Arc::clone(stop);
     pool.execute(move || {
-        let mut buffer = vec![
-            material::Color {
-                red: 0.0,
-                green: 0.0,
-                blue: 0.0
-            };
-            width * height
-        ];
-        let mut rng = rand_xorshift::XorShiftRng::from_entropy();
-        for y in 0..height {
-            for x in 0..width {
-                for _ in 0..rays_per_pixel {
-                    let ray = generate_camera_ray(&my_camera, &mut rng, x, y, width, height);
-                    let val = sample(&my_scene, ray, &mut rng);
-                    buffer[width * y + x] += val;
+        if my_stop.load(Ordering::Relaxed) {
+            return;
+        }
+        let tile = job.tile;
+        let mut film = film::Film::new(tile.width, tile.height);
+        let mut sampler =
+            sampler_type.create(seed, stream_seed(seed, job.index as u64), samples_per_pixel);
+        for y in 0..tile.height {
+            for x in 0..tile.width {
+                if job.active.as_ref().is_some_and(|a| !a[tile.width * y + x]) {
+                    continue;
+                }
+                for i in 0..job.samples {
+                    let sampler = sampler.as_mut();
+                    sampler.start_pixel_sample(tile.x + x, tile.y + y, job.first_sample + i);
+                    let ray = generate_camera_ray(
+                        &my_camera,
+                        sampler,
+                        tile.x + x,
+                        tile.y + y,
+                        width,
+                        height,
+                    );
+                    let val = if spectral {
+                        let wavelengths = spectrum::sample_wavelengths(sampler.get_1d());
+                        let val = sample(
+                            &my_scene,
+                            ray,
+                            Channels::Spectral(wavelengths),
+                            depth,
+                            sampler,
+                        );
+                        spectrum::to_rgb(val, wavelengths)
+                    } else {
+                        sample(&my_scene, ray, Channels::Rgb, depth, sampler)
+                    };
+                    film.add_sample(x, y, val);
                 }
             }
         }
         // The receiver may have shut down and then we send the data into the void.
-        let _ = my_tx.send(buffer);
+        let _ = my_tx.send(TileResult { job, film });
     });
 }
 
+/// The part of a full image's per pixel values covering `tile`.
+fn tile_pixels<T: Copy>(image: &[T], width: usize, tile: &film::Tile) -> Vec<T> {
+    (tile.y..tile.y + tile.height)
+        .flat_map(|y| &image[width * y + tile.x..width * y + tile.x + tile.width])
+        .copied()
+        .collect()
+}
+
+/// Seed for the random number stream of one job, mixed with SplitMix64 so
+/// neighbouring indices give unrelated streams.
+fn stream_seed(seed: u64, index: u64) -> u64 {
+    let mut z = seed ^ index.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Renders on from `checkpoint`, until done or `stop` is set, and returns
+/// how far it got.
 pub fn render(
     preview_window: &Option<preview::Preview>,
     scene: Arc<scene::Scene>,
     camera: Arc<scene::Camera>,
-    width: usize,
-    height: usize,
-    rays_per_pixel: i64,
-) -> Vec<u8> {
-    let num_jobs = rays_per_pixel / 10;
-    let (tx, rx) = mpsc::channel();
-    let pool = threadpool::ThreadPool::new(THREADS as usize);
-    println!("Running on {} cores", THREADS);
+    settings: &Settings,
+    mut checkpoint: Checkpoint,
+    autosave: Option<&Autosave>,
+    stop: &Arc<AtomicBool>,
+) -> Checkpoint {
+    let tiles = film::tiles(settings.width, settings.height, TILE_SIZE);
+    let num_passes = settings.samples_per_pixel.div_ceil(SAMPLES_PER_JOB);
+    let num_jobs = num_passes as usize * tiles.len();
+    let start = Instant::now();
+    let pool = threadpool::ThreadPool::new(settings.threads);
+    println!("Running on {} cores", settings.threads);
     println!("Spawining {} jobs", num_jobs);
-    for _i in 0..num_jobs {
-        start_render_job(
-            &pool,
-            &scene,
-            &camera,
-            &tx,
-            width,
-            height,
-            rays_per_pixel / num_jobs,
-        );
-    }
-    drop(tx);
+    // Every pass covers the whole image once, so the preview fills in tile by
+    // tile and then gets less noisy with each pass. Normally they all go out
+    // at once. With a noise threshold or limits every pass after the first
+    // few waits for the ones before it, to see which pixels still need work
+    // and not to queue up more than can be cut short.
+    let progressive = settings.noise_threshold.is_some()
+        || settings.time_limit.is_some()
+        || settings.target_error.is_some();
+    let first = if progressive {
+        ADAPTIVE_FIRST_PASSES.min(num_passes)
+    } else {
+        num_passes
+    };
+    let waves = std::iter::once(0..first).chain((first..num_passes).map(|pass| pass..pass + 1));
 
-    let mut accumulator = vec![
-        material::Color {
-            red: 0.0,
-            green: 0.0,
-            blue: 0.0
-        };
-        width * height
-    ];
-    let mut img_buffer = vec![0; width * height * 4];
     let mut finished_jobs = 0;
-    for buffer in rx {
-        finished_jobs += 1;
-        print!("\r{:.2}%", 100. * finished_jobs as f32 / num_jobs as f32);
-        io::stdout().flush().unwrap();
-        for (i, val) in buffer.iter().enumerate() {
-            accumulator[i] += *val;
-        }
-        let factor = compute_gain(&accumulator);
-
-        let mut i = 0;
-        for val in accumulator.iter() {
-            img_buffer[i + 0] = (val.red * factor) as u8;
-            img_buffer[i + 1] = (val.green * factor) as u8;
-            img_buffer[i + 2] = (val.blue * factor) as u8;
-            img_buffer[i + 3] = 255;
-            i += 4;
-        }
-
-        if let Some(p) = preview_window {
-            let res = p.submit_image(&img_buffer);
-            match res {
-                Err(_) => {
+    let mut last_preview = Instant::now();
+    let mut last_save = Instant::now();
+    'waves: for passes in waves {
+        let wave_end = passes.end as usize * tiles.len();
+        if passes.start > 0 {
+            if let Some(target) = settings.target_error {
+                let error = checkpoint.film.mean_relative_error();
+                if error < target {
                     println!();
-                    println!("Stopped, outputting image...");
+                    println!("Reached an error of {:.4}, outputting image...", error);
                     break;
                 }
-                _ => {}
             }
         }
-    }
-    println!();
-    img_buffer
-}
-
-fn compute_gain(buffer: &Vec<material::Color>) -> f32 {
-    let mut max = 0.;
-    for &val in buffer {
-        if val.red > max {
-            max = val.red;
+        let active = match settings.noise_threshold {
+            Some(threshold) if passes.start > 0 => Some(
+                (0..settings.height)
+                    .flat_map(|y| (0..settings.width).map(move |x| (x, y)))
+                    .map(|(x, y)| checkpoint.film.error(x, y) > threshold)
+                    .collect::<Vec<bool>>(),
+            ),
+            _ => None,
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut indices = Vec::new();
+        for pass in passes {
+            let samples = SAMPLES_PER_JOB.min(settings.samples_per_pixel - pass * SAMPLES_PER_JOB);
+            for (i, tile) in tiles.iter().enumerate() {
+                if pass as usize * tiles.len() + i < checkpoint.jobs_done {
+                    // Done before resuming.
+                    finished_jobs += 1;
+                    continue;
+                }
+                let job = Job {
+                    index: pass as usize * tiles.len() + i,
+                    tile: *tile,
+                    first_sample: pass * SAMPLES_PER_JOB,
+                    samples,
+                    active: active
+                        .as_ref()
+                        .map(|a| tile_pixels(a, settings.width, tile)),
+                };
+                if job.active.as_ref().is_some_and(|a| !a.contains(&true)) {
+                    // Converged already.
+                    finished_jobs += 1;
+                    continue;
+                }
+                indices.push(job.index);
+                start_render_job(&pool, &scene, &camera, &tx, stop, settings, job);
+            }
         }
-        if val.green > max {
-            max = val.green;
+        drop(tx);
+
+        // Jobs finish in any order, but floating point sums depend on the
+        // order of addition. Merge them by index to keep the image
+        // reproducible.
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        for result in rx {
+            finished_jobs += 1;
+            let mut done = finished_jobs as f32 / num_jobs as f32;
+            if let Some(limit) = settings.time_limit {
+                done = done.max(start.elapsed().as_secs_f32() / limit.as_secs_f32());
+            }
+            print!("\r{:.2}%", 100. * done.min(1.0));
+            io::stdout().flush().unwrap();
+            pending.insert(result.job.index, result);
+            while let Some(result) = indices.get(next).and_then(|i| pending.remove(i)) {
+                checkpoint.film.add_tile(&result.job.tile, &result.film);
+                next += 1;
+            }
+            // Skipped jobs count as done once everything before them is.
+            checkpoint.jobs_done = indices.get(next).copied().unwrap_or(wave_end);
+
+            if settings
+                .time_limit
+                .is_some_and(|limit| start.elapsed() >= limit)
+            {
+                println!();
+                println!("Out of time, outputting image...");
+                stop.store(true, Ordering::Relaxed);
+                break 'waves;
+            }
+            if stop.load(Ordering::Relaxed) {
+                break;
+            }
+
+            if let Some(autosave) = autosave {
+                if last_save.elapsed() >= autosave.interval {
+                    last_save = Instant::now();
+                    if let Err(e) = checkpoint.save(&autosave.path) {
+                        eprintln!("{}: {}", autosave.path.display(), e);
+                    }
+                }
+            }
+
+            if let Some(p) = preview_window {
+                if finished_jobs < num_jobs && last_preview.elapsed() < PREVIEW_INTERVAL {
+                    continue;
+                }
+                last_preview = Instant::now();
+                if p.submit_image(&checkpoint.film.to_rgba8(&settings.tone_mapping))
+                    .is_err()
+                {
+                    println!();
+                    println!("Stopped, outputting image...");
+                    stop.store(true, Ordering::Relaxed);
+                    break 'waves;
+                }
+            }
         }
-        if val.blue > max {
-            max = val.blue;
+        if stop.load(Ordering::Relaxed) {
+            println!();
+            println!("Interrupted, outputting image...");
+            break;
         }
     }
-    return 255. / max;
+    println!();
+    checkpoint
 }
 
-fn sample(scene: &scene::Scene, initial_ray: Ray, rng: &mut XorShiftRng) -> material::Color {
+fn sample(
+    scene: &scene::Scene,
+    initial_ray: Ray,
+    channels: Channels,
+    depth: Depth,
+    sampler: &mut dyn Sampler,
+) -> material::Color {
     let mut ray = material::LightRay {
         ray: initial_ray,
         light: material::Color {
@@ -144,51 +342,183 @@ fn sample(scene: &scene::Scene, initial_ray: Ray, rng: &mut XorShiftRng) -> mate
         },
         ior: 1.,
         count: 0,
-        done: false,
+        specular: true,
+        pdf: 0.0,
+        channels,
+    };
+    let mut radiance = material::Color {
+        red: 0.0,
+        green: 0.0,
+        blue: 0.0,
     };
     loop {
-        match shoot_ray(&scene, &ray.ray) {
-            Some((
-                obj,
-                Intersection {
-                    point,
-                    normal,
-                    inside,
-                    ..
-                },
-            )) => {
-                ray = obj.material.new_ray(ray, point, normal, inside, rng);
+        let dimension = CAMERA_DIMENSIONS + BOUNCE_DIMENSIONS * ray.count as u32;
+        match scene.intersect(&ray.ray) {
+            Some((obj, hit)) => {
+                if hit.inside {
+                    // The path got here through the object's interior.
+                    ray.light = ray.light * obj.material.transmittance(hit.distance, &channels);
+                }
+                if obj.material.is_emissive() {
+                    // Lights reached through a diffuse bounce might also have
+                    // been found by light sampling, weigh the two together.
+                    let weight = if ray.specular {
+                        1.0
+                    } else {
+                        let light_pdf = scene.light_pdf(obj, ray.ray.origin, ray.ray.direction);
+                        power_heuristic(ray.pdf, light_pdf)
+                    };
+                    radiance += ray.light * obj.material.emission(&hit, &channels) * weight;
+                    return radiance;
+                }
+                if ray.count as u32 >= depth.max {
+                    // Only traced this far for the light it might find.
+                    return radiance;
+                }
+                let hit = obj.material.bumped(hit);
+                let throughput = ray.light;
+                let incoming = ray.ray.direction;
+                sampler.set_dimension(dimension);
+                ray = obj.material.new_ray(ray, &hit, sampler);
+                // Whichever lobe the path took, since `eval` already weighs
+                // the diffuse one by the chance of picking it.
+                if !obj.material.is_specular() {
+                    sampler.set_dimension(dimension + LIGHT_DIMENSION);
+                    radiance += throughput
+                        * sample_light(scene, &obj.material, &hit, incoming, &channels, sampler);
+                }
             }
             None => {
-                return ray.light;
+                let direction = ray.ray.direction;
+                // Weighed against light sampling like emissive objects.
+                let weight = if ray.specular || !scene.environment.is_sampled() {
+                    1.0
+                } else {
+                    power_heuristic(ray.pdf, scene.environment_pdf(direction))
+                };
+                radiance +=
+                    ray.light * channels.color(scene.environment.radiance(direction)) * weight;
+                return radiance;
             }
         }
-        if ray.done || ray.count > 100 {
-            return ray.light;
+        if ray.light.is_black() {
+            return radiance;
+        }
+        if ray.count as u32 >= depth.min {
+            // Go on with a chance following the light the path still carries,
+            // making up for the ones that end by weighing up the survivors.
+            let survival = ray.light.max_component().min(1.0);
+            sampler.set_dimension(dimension + ROULETTE_DIMENSION);
+            if sampler.get_1d() >= survival {
+                return radiance;
+            }
+            ray.light = ray.light * (1.0 / survival);
         }
     }
 }
 
-fn shoot_ray<'a>(scene: &'a scene::Scene, ray: &Ray) -> Option<(&'a scene::Object, Intersection)> {
-    let mut closest_intersection: Option<(&'a scene::Object, Intersection)> = None;
-    for obj in scene.objs.iter() {
-        match obj.shape.intersect(&ray) {
-            Some(intersection) => {
-                if !closest_intersection.is_some()
-                    || intersection.distance < closest_intersection.unwrap().1.distance
-                {
-                    closest_intersection = Some((obj, intersection));
-                }
-            }
-            None => {}
+/// Direct lighting at a diffuse hit from one randomly picked light, weighted
+/// against the light being found by the BSDF sample.
+fn sample_light(
+    scene: &scene::Scene,
+    material: &material::Material,
+    hit: &Intersection,
+    incoming: Vector,
+    channels: &Channels,
+    sampler: &mut dyn Sampler,
+) -> material::Color {
+    let black = material::Color {
+        red: 0.0,
+        green: 0.0,
+        blue: 0.0,
+    };
+    let point = hit.point;
+    let emitters = scene.emitters();
+    let count = scene.light_count();
+    if count == 0 {
+        return black;
+    }
+    let picked = ((sampler.get_1d() * count as f32) as usize).min(count - 1);
+    if let Some(light) = picked
+        .checked_sub(emitters.len())
+        .and_then(|i| scene.lights.get(i))
+    {
+        return sample_delta_light(scene, light, material, hit, incoming, channels) * count as f32;
+    }
+    // None for the environment, which comes after the lights.
+    let light = emitters.get(picked).map(|&i| &scene.objs[i]);
+    let (u1, u2) = sampler.get_2d();
+    let sampled = match light {
+        Some(light) => light.shape.sample_towards(point, u1, u2),
+        None => scene.environment.sample(u1, u2),
+    };
+    let (direction, light_pdf) = match sampled {
+        Some((direction, pdf)) => (direction, pdf / count as f32),
+        None => return black,
+    };
+    let cos_theta = dot(direction, hit.shading_normal);
+    if cos_theta <= 0.0 || dot(direction, hit.normal) <= 0.0 {
+        return black;
+    }
+    let shadow_ray = Ray {
+        origin: offset_origin(point, hit.normal, direction),
+        direction,
+    };
+    let emission = match (light, scene.intersect(&shadow_ray)) {
+        (Some(light), Some((obj, light_hit))) if std::ptr::eq(obj, light) => {
+            light.material.emission(&light_hit, channels)
+        }
+        (None, None) => channels.color(scene.environment.radiance(direction)),
+        _ => return black,
+    };
+    let (f, bsdf_pdf) = material.eval(incoming, direction, hit, channels);
+    emission * f * (cos_theta * power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+}
+
+/// Light arriving from a point-like light, which no BSDF sample can find,
+/// so there is nothing to weigh it against.
+fn sample_delta_light(
+    scene: &scene::Scene,
+    light: &light::Light,
+    material: &material::Material,
+    hit: &Intersection,
+    incoming: Vector,
+    channels: &Channels,
+) -> material::Color {
+    let black = material::Color {
+        red: 0.0,
+        green: 0.0,
+        blue: 0.0,
+    };
+    let (direction, distance, irradiance) = match light.illuminate(hit.point) {
+        Some(lit) => lit,
+        None => return black,
+    };
+    let cos_theta = dot(direction, hit.shading_normal);
+    if cos_theta <= 0.0 || dot(direction, hit.normal) <= 0.0 {
+        return black;
+    }
+    let shadow_ray = Ray {
+        origin: offset_origin(hit.point, hit.normal, direction),
+        direction,
+    };
+    if let Some((_, blocker)) = scene.intersect(&shadow_ray) {
+        if blocker.distance < distance {
+            return black;
         }
     }
-    closest_intersection
+    let (f, _) = material.eval(incoming, direction, hit, channels);
+    channels.color(irradiance) * f * cos_theta
+}
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let p2 = pdf * pdf;
+    p2 / (p2 + other_pdf * other_pdf)
 }
 
 fn generate_camera_ray(
     cam: &scene::Camera,
-    rng: &mut XorShiftRng,
+    sampler: &mut dyn Sampler,
     x: usize,
     y: usize,
     width: usize,
@@ -199,11 +529,11 @@ fn generate_camera_ray(
     let down = cross(cam.direction, right).normalize();
 
     let x_range = (cam.fov / 2.0).tan();
-    let y_range = x_range / cam.aspect;
+    let y_range = x_range * height as f32 / width as f32;
     // Goes from -1 to 1
-    let param_x = 2.0 * ((x as f32 / width as f32) + (1. / width as f32) * rng.gen::<f32>()) - 1.0;
-    let param_y =
-        2.0 * ((y as f32 / height as f32) + (1. / height as f32) * rng.gen::<f32>()) - 1.0;
+    let (jitter_x, jitter_y) = sampler.get_2d();
+    let param_x = 2.0 * ((x as f32 / width as f32) + (1. / width as f32) * jitter_x) - 1.0;
+    let param_y = 2.0 * ((y as f32 / height as f32) + (1. / height as f32) * jitter_y) - 1.0;
 
     let p_x = x_range * param_x;
     let p_y = y_range * param_y;
@@ -214,19 +544,699 @@ fn generate_camera_ray(
     let displacement = through_screen - origin;
     let through = translate(origin, cam.focal_distance * displacement);
 
-    // perturb ray
-    let mut perturbation_param_x = 2.0;
-    let mut perturbation_param_y = 2.0;
-    while perturbation_param_x * perturbation_param_x + perturbation_param_y * perturbation_param_y
-        > 1.0
-    {
-        perturbation_param_x = 2.0 * rng.gen::<f32>() - 1.0;
-        perturbation_param_y = 2.0 * rng.gen::<f32>() - 1.0;
-    }
-    let perturbation_x = perturbation_param_x * cam.aperture;
-    let perturbation_y = perturbation_param_y * cam.aperture;
+    // Perturb the ray by a uniform point on the lens, drawn even without an
+    // aperture so the dimensions after it stay put.
+    let (u1, u2) = sampler.get_2d();
+    let r = u1.sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u2;
+    let perturbation_x = r * phi.cos() * cam.aperture;
+    let perturbation_y = r * phi.sin() * cam.aperture;
 
     let perturbed_origin = translate(origin, (perturbation_x * right) + (perturbation_y * down));
 
     Ray::create(perturbed_origin, through)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checkpoint::SceneId;
+    use crate::environment::{Environment, EnvironmentMap};
+    use crate::light::Light;
+    use crate::material::{Color, Material};
+    use crate::sampler::Independent;
+    use crate::texture::{ImageTexture, Wrap};
+
+    fn test_scene() -> (Arc<scene::Scene>, Arc<scene::Camera>) {
+        let mut scene = scene::Scene::new();
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 0.0,
+                    y: 5.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+            }),
+            material: Material::create(
+                Color {
+                    red: 0.8,
+                    green: 0.5,
+                    blue: 0.2,
+                },
+                1.0,
+                0.0,
+            ),
+        });
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 2.0,
+                    y: 4.0,
+                    z: 2.0,
+                },
+                radius: 0.5,
+            }),
+            material: Material::create_emissive(Color {
+                red: 4.0,
+                green: 4.0,
+                blue: 4.0,
+            }),
+        });
+        scene.build();
+        let camera = scene::Camera {
+            look_from: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+            up: Vector {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+            fov: 0.8,
+            aperture: 0.0,
+            focal_distance: 5.0,
+        };
+        (Arc::new(scene), Arc::new(camera))
+    }
+
+    fn test_settings(threads: usize, seed: u64) -> Settings {
+        Settings {
+            width: 8,
+            height: 6,
+            samples_per_pixel: 45,
+            threads,
+            seed,
+            spectral: false,
+            depth: Depth::default(),
+            sampler: SamplerType::Sobol,
+            noise_threshold: None,
+            time_limit: None,
+            target_error: None,
+            tone_mapping: film::ToneMapping {
+                operator: film::ToneMap::Linear,
+                exposure: 0.0,
+            },
+        }
+    }
+
+    fn new_checkpoint(settings: &Settings) -> Checkpoint {
+        let scene = SceneId {
+            path: "test.toml".to_string(),
+            hash: 0,
+        };
+        Checkpoint::new(settings, scene)
+    }
+
+    fn render_from(
+        scene: &Arc<scene::Scene>,
+        camera: &Arc<scene::Camera>,
+        settings: &Settings,
+        checkpoint: Checkpoint,
+    ) -> Checkpoint {
+        let stop = Arc::new(AtomicBool::new(false));
+        render(
+            &None,
+            Arc::clone(scene),
+            Arc::clone(camera),
+            settings,
+            checkpoint,
+            None,
+            &stop,
+        )
+    }
+
+    fn render_film(
+        scene: &Arc<scene::Scene>,
+        camera: &Arc<scene::Camera>,
+        settings: &Settings,
+    ) -> film::Film {
+        let checkpoint = new_checkpoint(settings);
+        render_from(scene, camera, settings, checkpoint).film
+    }
+
+    fn render_with(threads: usize, seed: u64) -> Vec<u32> {
+        let (scene, camera) = test_scene();
+        bits(&render_film(&scene, &camera, &test_settings(threads, seed)))
+    }
+
+    fn bits(film: &film::Film) -> Vec<u32> {
+        let mut bits = Vec::new();
+        for y in 0..film.height {
+            for x in 0..film.width {
+                let val = film.pixel(x, y);
+                bits.extend([val.red.to_bits(), val.green.to_bits(), val.blue.to_bits()]);
+            }
+        }
+        bits
+    }
+
+    #[test]
+    fn same_seed_same_image() {
+        assert!(render_with(1, 7) == render_with(3, 7));
+    }
+
+    #[test]
+    fn different_seed_different_image() {
+        assert!(render_with(2, 7) != render_with(2, 8));
+    }
+
+    #[test]
+    fn noise_threshold_stops_sampling() {
+        let (scene, camera) = test_scene();
+        let mut settings = test_settings(2, 7);
+        let film = render_film(&scene, &camera, &settings);
+        assert_eq!(45 * 48, film.total_samples());
+        // Nothing is ever noisier than infinity, only the first passes run.
+        settings.noise_threshold = Some(f32::INFINITY);
+        let film = render_film(&scene, &camera, &settings);
+        assert_eq!(20 * 48, film.total_samples());
+    }
+
+    #[test]
+    fn limits_end_rendering_early() {
+        let (scene, camera) = test_scene();
+        let mut settings = test_settings(2, 7);
+        settings.target_error = Some(f32::INFINITY);
+        let film = render_film(&scene, &camera, &settings);
+        assert_eq!(20 * 48, film.total_samples());
+        // Stops at the first finished job, which may not be the first one to
+        // merge.
+        settings.target_error = None;
+        settings.time_limit = Some(Duration::ZERO);
+        let film = render_film(&scene, &camera, &settings);
+        assert!(film.total_samples() <= 10 * 48);
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        let (scene, camera) = test_scene();
+        let mut settings = test_settings(2, 7);
+        let fresh = new_checkpoint(&settings);
+        let stop = Arc::new(AtomicBool::new(true));
+        let stopped = render(
+            &None,
+            Arc::clone(&scene),
+            Arc::clone(&camera),
+            &settings,
+            fresh,
+            None,
+            &stop,
+        );
+        assert_eq!(0, stopped.jobs_done);
+        assert_eq!(0, stopped.film.total_samples());
+
+        settings.target_error = Some(f32::INFINITY);
+        let partial = render_from(&scene, &camera, &settings, stopped);
+        assert_eq!(2, partial.jobs_done);
+        // Carrying on adds the same samples in the same order as rendering
+        // in one go.
+        settings.target_error = None;
+        let resumed = render_from(&scene, &camera, &settings, partial);
+        assert_eq!(5, resumed.jobs_done);
+        assert!(bits(&resumed.film) == render_with(2, 7));
+    }
+
+    #[test]
+    fn white_furnace() {
+        // A white diffuse sphere under a uniform white sky reflects exactly
+        // what it receives, so every path carries a radiance of one.
+        let mut scene = scene::Scene::new();
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 0.0,
+                    y: 5.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+            }),
+            material: Material::create(
+                Color {
+                    red: 1.0,
+                    green: 1.0,
+                    blue: 1.0,
+                },
+                1.0,
+                0.0,
+            ),
+        });
+        scene.build();
+        let mut sampler = Independent::new(5);
+        for _ in 0..1000 {
+            let target = Point {
+                x: 1.8 * sampler.get_1d() - 0.9,
+                y: 4.0,
+                z: 1.8 * sampler.get_1d() - 0.9,
+            };
+            let ray = Ray::create(
+                Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                target,
+            );
+            let val = sample(&scene, ray, Channels::Rgb, Depth::default(), &mut sampler);
+            assert!((val.red - 1.0).abs() < 1e-4, "{}", val.red);
+        }
+    }
+
+    /// A floor lit by a glowing sphere under a black sky, so light only
+    /// ever reaches it directly.
+    fn lit_floor(ior: f32) -> scene::Scene {
+        let mut scene = scene::Scene::new();
+        scene.environment = Environment::Constant(Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        });
+        scene.objs.push(scene::Object {
+            shape: Box::new(Plane {
+                point: Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                normal: Vector {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+            }),
+            material: Material::create(
+                Color {
+                    red: 0.8,
+                    green: 0.8,
+                    blue: 0.8,
+                },
+                ior,
+                0.0,
+            ),
+        });
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 2.0,
+                },
+                radius: 1.0,
+            }),
+            material: Material::create_emissive(Color {
+                red: 1.0,
+                green: 1.0,
+                blue: 1.0,
+            }),
+        });
+        scene.build();
+        scene
+    }
+
+    fn floor_ray() -> Ray {
+        Ray::create(
+            Point {
+                x: 3.0,
+                y: 0.0,
+                z: 3.0,
+            },
+            Point {
+                x: 1.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        )
+    }
+
+    fn mean_red(scene: &scene::Scene, depth: Depth, n: usize) -> f64 {
+        let mut sampler = Independent::new(8);
+        let mut sum = 0.0;
+        for _ in 0..n {
+            sum += sample(scene, floor_ray(), Channels::Rgb, depth, &mut sampler).red as f64;
+        }
+        sum / n as f64
+    }
+
+    #[test]
+    fn light_sampling_matches_bsdf_sampling() {
+        // Light sampling weighed against the BSDF must give what the BSDF
+        // samples alone find, also with the coat reflecting part of the light
+        // away.
+        for ior in [1.0, 1.5, 3.0] {
+            let scene = lit_floor(ior);
+            let n = 100000;
+            let with_lights = mean_red(&scene, Depth::default(), n);
+            let mut sampler = Independent::new(9);
+            let mut bsdf_only = 0.0;
+            for _ in 0..n {
+                let (obj, hit) = scene.intersect(&floor_ray()).unwrap();
+                let path = material::LightRay {
+                    ray: floor_ray(),
+                    light: Color {
+                        red: 1.0,
+                        green: 1.0,
+                        blue: 1.0,
+                    },
+                    ior: 1.0,
+                    count: 0,
+                    specular: true,
+                    pdf: 0.0,
+                    channels: Channels::Rgb,
+                };
+                let path = obj.material.new_ray(path, &hit, &mut sampler);
+                if let Some((light, light_hit)) = scene.intersect(&path.ray) {
+                    let emission = light.material.emission(&light_hit, &Channels::Rgb);
+                    bsdf_only += (path.light * emission).red as f64;
+                }
+            }
+            let bsdf_only = bsdf_only / n as f64;
+            assert!(
+                (with_lights - bsdf_only).abs() < 0.02 * bsdf_only,
+                "{} {} {}",
+                ior,
+                with_lights,
+                bsdf_only
+            );
+        }
+    }
+
+    #[test]
+    fn direct_lighting_depth() {
+        // Nothing but direct light reaches the floor, so cutting paths off
+        // after the first bounce mustn't lose any of it.
+        let scene = lit_floor(1.5);
+        let direct = mean_red(&scene, Depth { min: 1, max: 1 }, 100000);
+        let full = mean_red(&scene, Depth { min: 2, max: 2 }, 100000);
+        assert!((direct - full).abs() < 0.01 * full, "{} {}", direct, full);
+    }
+
+    #[test]
+    fn environment_map_furnace() {
+        // Like the white furnace with a uniform map, found both by light
+        // sampling and by the BSDF samples, so only the average is one.
+        let mut scene = scene::Scene::new();
+        let white = Color {
+            red: 1.0,
+            green: 1.0,
+            blue: 1.0,
+        };
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 0.0,
+                    y: 5.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+            }),
+            material: Material::create(white, 1.0, 0.0),
+        });
+        let image = ImageTexture::new(8, 4, vec![white; 32], Wrap::Clamp);
+        scene.environment = Environment::Map(EnvironmentMap::new(image, 1.0, 0.3));
+        scene.build();
+        let mut sampler = Independent::new(5);
+        let n = 20000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let target = Point {
+                x: 1.8 * sampler.get_1d() - 0.9,
+                y: 4.0,
+                z: 1.8 * sampler.get_1d() - 0.9,
+            };
+            let ray = Ray::create(
+                Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                target,
+            );
+            sum += sample(&scene, ray, Channels::Rgb, Depth::default(), &mut sampler).red as f64;
+        }
+        let mean = sum / n as f64;
+        assert!((mean - 1.0).abs() < 0.02, "{}", mean);
+    }
+
+    #[test]
+    fn point_light_shadow() {
+        // Light no ray can hit still lights a grey floor under a black sky,
+        // except where a sphere is in the way.
+        let mut scene = scene::Scene::new();
+        let black = Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        };
+        scene.environment = Environment::Constant(black);
+        let up = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        scene.objs.push(scene::Object {
+            shape: Box::new(Plane {
+                point: Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                normal: up,
+            }),
+            material: Material::create(
+                Color {
+                    red: 0.5,
+                    green: 0.5,
+                    blue: 0.5,
+                },
+                1.0,
+                0.0,
+            ),
+        });
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 3.0,
+                    y: 0.0,
+                    z: 1.0,
+                },
+                radius: 0.5,
+            }),
+            material: Material::create(black, 1.0, 0.0),
+        });
+        scene.lights.push(Light::Point {
+            position: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 2.0,
+            },
+            intensity: Color {
+                red: 4.0,
+                green: 4.0,
+                blue: 4.0,
+            },
+        });
+        scene.build();
+        let mut sampler = Independent::new(5);
+        let down = |x: f32| Ray {
+            origin: Point { x, y: 0.0, z: 5.0 },
+            direction: -up,
+        };
+        // Irradiance of one reflected by an albedo of a half.
+        let lit = sample(
+            &scene,
+            down(0.0),
+            Channels::Rgb,
+            Depth::default(),
+            &mut sampler,
+        );
+        assert!(
+            (lit.green - 0.5 / std::f32::consts::PI).abs() < 1e-4,
+            "{}",
+            lit.green
+        );
+        let shadowed = sample(
+            &scene,
+            down(6.0),
+            Channels::Rgb,
+            Depth::default(),
+            &mut sampler,
+        );
+        assert_eq!(0.0, shadowed.green);
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        // Inside a grey sphere lit from its center paths bounce on and on,
+        // ending them at random mustn't change what they carry on average.
+        let mut scene = scene::Scene::new();
+        let center = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center,
+                radius: 2.0,
+            }),
+            material: Material::create(
+                Color {
+                    red: 0.5,
+                    green: 0.5,
+                    blue: 0.5,
+                },
+                1.0,
+                0.0,
+            ),
+        });
+        scene.lights.push(Light::Point {
+            position: center,
+            intensity: Color {
+                red: 4.0,
+                green: 4.0,
+                blue: 4.0,
+            },
+        });
+        scene.build();
+        let mean = |depth: Depth| {
+            let mut sampler = Independent::new(5);
+            let n = 20000;
+            let mut sum = 0.0;
+            for _ in 0..n {
+                let z: f32 = 2.0 * sampler.get_1d() - 1.0;
+                let phi = 2.0 * std::f32::consts::PI * sampler.get_1d();
+                let r = (1.0 - z * z).sqrt();
+                let ray = Ray {
+                    origin: center,
+                    direction: Vector {
+                        x: r * phi.cos(),
+                        y: r * phi.sin(),
+                        z,
+                    },
+                };
+                sum += sample(&scene, ray, Channels::Rgb, depth, &mut sampler).red as f64;
+            }
+            (sum / n as f64) as f32
+        };
+        // Half the light is left after each bounce, after 40 nothing is.
+        let full = mean(Depth { min: 40, max: 40 });
+        let roulette = mean(Depth { min: 1, max: 40 });
+        assert!(
+            (full - roulette).abs() < 0.02 * full,
+            "{} {}",
+            full,
+            roulette
+        );
+    }
+
+    #[test]
+    fn absorption_through_sphere() {
+        // Light passing straight through the middle travels twice the radius
+        // inside, and an index of refraction of one doesn't reflect any.
+        let mut scene = scene::Scene::new();
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 0.0,
+                    y: 5.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+            }),
+            material: Material::create_dielectric(
+                1.0,
+                Color {
+                    red: 0.5,
+                    green: 1.0,
+                    blue: 0.1,
+                },
+                1.0,
+            ),
+        });
+        scene.build();
+        let mut sampler = Independent::new(6);
+        let ray = Ray {
+            origin: Point {
+                x: 0.0,
+                y: 0.0,
+                z: 0.0,
+            },
+            direction: Vector {
+                x: 0.0,
+                y: 1.0,
+                z: 0.0,
+            },
+        };
+        let val = sample(&scene, ray, Channels::Rgb, Depth::default(), &mut sampler);
+        assert!((val.red - 0.25).abs() < 1e-3, "{}", val.red);
+        assert!((val.green - 1.0).abs() < 1e-3, "{}", val.green);
+        assert!((val.blue - 0.01).abs() < 1e-3, "{}", val.blue);
+    }
+
+    #[test]
+    fn spectral_white_furnace() {
+        // Same as `white_furnace`, the wavelength samples only add noise.
+        let mut scene = scene::Scene::new();
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: Point {
+                    x: 0.0,
+                    y: 5.0,
+                    z: 0.0,
+                },
+                radius: 1.0,
+            }),
+            material: Material::create(
+                Color {
+                    red: 1.0,
+                    green: 1.0,
+                    blue: 1.0,
+                },
+                1.5,
+                0.0,
+            ),
+        });
+        scene.build();
+        let mut sampler = Independent::new(7);
+        let n = 20000;
+        let mut sum = Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        };
+        for _ in 0..n {
+            let ray = Ray {
+                origin: Point {
+                    x: 0.0,
+                    y: 0.0,
+                    z: 0.0,
+                },
+                direction: Vector {
+                    x: 0.0,
+                    y: 1.0,
+                    z: 0.0,
+                },
+            };
+            let wavelengths = spectrum::sample_wavelengths(sampler.get_1d());
+            let val = sample(
+                &scene,
+                ray,
+                Channels::Spectral(wavelengths),
+                Depth::default(),
+                &mut sampler,
+            );
+            sum += spectrum::to_rgb(val, wavelengths);
+        }
+        let mean = sum * (1.0 / n as f32);
+        for c in [mean.red, mean.green, mean.blue] {
+            assert!((c - 1.0).abs() < 0.02, "{}", c);
+        }
+    }
+}
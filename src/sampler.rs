@@ -0,0 +1,483 @@
+//! Where the random numbers of a path come from. Besides plain random
+//! numbers, samplers can spread the samples of a pixel evenly over each
+//! dimension, which converges faster than white noise.
+//!
+//! Every pixel sample draws from a sequence of dimensions, one per number:
+//! the same dimension of different samples of a pixel is what gets spread
+//! out, so callers should draw the same quantity from the same dimension.
+
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+pub trait Sampler {
+    /// Starts sample `index` of pixel (`x`, `y`) at the first dimension.
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32);
+    /// Skips ahead (or back) to `dimension`, so a bounce draws from the same
+    /// dimensions whatever the bounces before it took.
+    fn set_dimension(&mut self, dimension: u32);
+    /// Number in [0, 1) from the next dimension.
+    fn get_1d(&mut self) -> f32;
+    /// Pair of numbers in [0, 1) from the next two dimensions, spread out
+    /// together over the unit square.
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+#[derive(Copy, Clone, PartialEq, clap::ValueEnum)]
+pub enum SamplerType {
+    /// Uniform random numbers
+    Independent,
+    /// Jittered strata, permuted per pixel and dimension
+    Stratified,
+    /// Halton sequence, scrambled per pixel; random after the first bounces
+    Halton,
+    /// Owen scrambled Sobol sequence
+    Sobol,
+}
+
+impl SamplerType {
+    /// Sampler for the pixel samples of one job. `stream` tells the jobs
+    /// apart for samplers that go by state rather than by sample index.
+    pub fn create(&self, seed: u64, stream: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            SamplerType::Independent => Box::new(Independent::new(stream)),
+            SamplerType::Stratified => Box::new(Stratified::new(seed, samples_per_pixel)),
+            SamplerType::Halton => Box::new(Halton::new(seed)),
+            SamplerType::Sobol => Box::new(Sobol::new(seed)),
+        }
+    }
+}
+
+/// Plain random numbers, ignoring pixels and dimensions.
+pub struct Independent {
+    rng: XorShiftRng,
+}
+
+impl Independent {
+    pub fn new(seed: u64) -> Independent {
+        Independent {
+            rng: XorShiftRng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Sampler for Independent {
+    fn start_pixel_sample(&mut self, _x: usize, _y: usize, _index: u32) {}
+
+    fn set_dimension(&mut self, _dimension: u32) {}
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
+
+/// Which number of which pixel sample is drawn next, for samplers computing
+/// each from scratch.
+#[derive(Copy, Clone, Default)]
+struct Position {
+    pixel: u64,
+    index: u32,
+    dimension: u32,
+}
+
+impl Position {
+    fn start(&mut self, x: usize, y: usize, index: u32) {
+        self.pixel = ((x as u64) << 32) | y as u64;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    /// Hash of the seed, the pixel and the current dimension, the same for
+    /// every sample of the pixel; moves on by `dimensions`.
+    fn next(&mut self, seed: u64, dimensions: u32) -> u64 {
+        let res = mix(mix(seed ^ self.pixel) ^ self.dimension as u64);
+        self.dimension += dimensions;
+        res
+    }
+}
+
+/// Jittered stratification: the samples of a pixel each fall into their own
+/// of as many equal strata per dimension (or square of them for pairs),
+/// visited in a different order for every pixel and dimension.
+pub struct Stratified {
+    seed: u64,
+    samples_per_pixel: u32,
+    position: Position,
+}
+
+impl Stratified {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> Stratified {
+        Stratified {
+            seed,
+            samples_per_pixel: samples_per_pixel.max(1),
+            position: Position::default(),
+        }
+    }
+}
+
+impl Sampler for Stratified {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let n = self.samples_per_pixel;
+        let index = self.position.index % n;
+        let hash = self.position.next(self.seed, 1);
+        let stratum = permute(index, n, hash as u32);
+        let jitter = to_unit(mix(hash ^ index as u64) as u32);
+        ((stratum as f32 + jitter) / n as f32).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // A grid with at least as many cells as there are samples, some left
+        // over if the count isn't a square.
+        let n = self.samples_per_pixel;
+        let columns = (n as f32).sqrt().ceil() as u32;
+        let rows = n.div_ceil(columns);
+        let index = self.position.index % n;
+        let hash = self.position.next(self.seed, 2);
+        let cell = permute(index, columns * rows, hash as u32);
+        let jitter = mix(hash ^ index as u64);
+        let a = ((cell % columns) as f32 + to_unit(jitter as u32)) / columns as f32;
+        let b = ((cell / columns) as f32 + to_unit((jitter >> 32) as u32)) / rows as f32;
+        (a.min(ONE_MINUS_EPSILON), b.min(ONE_MINUS_EPSILON))
+    }
+}
+
+/// Bases of the Halton dimensions, past which numbers are random.
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// The Halton sequence, the radical inverse of the sample index in a
+/// different prime base per dimension, with the digits shuffled per pixel
+/// and dimension (random digit scrambling) so pixels don't repeat each other
+/// and the few samples of a pixel spread out even in large bases. Dimensions
+/// past `PRIMES` get independent random numbers, which with the renderer's
+/// dimension layout covers the camera and about three bounces; deeper
+/// bounces contribute little and larger bases would stratify poorly anyway.
+pub struct Halton {
+    seed: u64,
+    position: Position,
+}
+
+impl Halton {
+    pub fn new(seed: u64) -> Halton {
+        Halton {
+            seed,
+            position: Position::default(),
+        }
+    }
+}
+
+impl Sampler for Halton {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.position.dimension as usize;
+        let index = self.position.index;
+        let hash = self.position.next(self.seed, 1);
+        match PRIMES.get(dimension) {
+            Some(&base) => scrambled_radical_inverse(index, base, hash),
+            None => to_unit(mix(hash ^ index as u64) as u32),
+        }
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+/// The Sobol sequence, with each dimension (or pair of dimensions for 2D
+/// samples) drawing from its own shuffle of the sample indices and Owen
+/// scrambled, following Burley's "Practical Hash-based Owen Scrambling"
+/// (2020). Only the first two Sobol dimensions are needed that way, and they
+/// stratify well at every power of two.
+pub struct Sobol {
+    seed: u64,
+    position: Position,
+}
+
+impl Sobol {
+    pub fn new(seed: u64) -> Sobol {
+        Sobol {
+            seed,
+            position: Position::default(),
+        }
+    }
+}
+
+impl Sampler for Sobol {
+    fn start_pixel_sample(&mut self, x: usize, y: usize, index: u32) {
+        self.position.start(x, y, index);
+    }
+
+    fn set_dimension(&mut self, dimension: u32) {
+        self.position.dimension = dimension;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let index = self.position.index;
+        let hash = self.position.next(self.seed, 1);
+        let shuffled = nested_uniform_scramble(index, hash as u32);
+        to_unit(nested_uniform_scramble(
+            shuffled.reverse_bits(),
+            (hash >> 32) as u32,
+        ))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let index = self.position.index;
+        let hash = self.position.next(self.seed, 2);
+        let shuffled = nested_uniform_scramble(index, hash as u32);
+        let second = mix(hash);
+        (
+            to_unit(nested_uniform_scramble(
+                shuffled.reverse_bits(),
+                (hash >> 32) as u32,
+            )),
+            to_unit(nested_uniform_scramble(
+                sobol_second(shuffled),
+                second as u32,
+            )),
+        )
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// SplitMix64's finalizer, scrambling all bits of `z`.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// The top 24 bits as a number in [0, 1).
+fn to_unit(bits: u32) -> f32 {
+    (bits >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+/// Element `i` of a random permutation of 0..`n` picked by `seed`, from
+/// Kensler's "Correlated Multi-Jittered Sampling" (2013).
+fn permute(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n.wrapping_sub(1);
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            return i.wrapping_add(seed) % n;
+        }
+    }
+}
+
+/// Digits of `index` in `base` mirrored around the point, each position's
+/// digit passed through its own permutation picked by `seed`.
+fn scrambled_radical_inverse(mut index: u32, base: u32, seed: u64) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut res = 0.0;
+    let mut scale = inverse_base;
+    let mut position = 0;
+    // The zeros past the index's last digit get permuted too, until they
+    // no longer make a difference.
+    while scale > 1e-8 {
+        let digit = permute(index % base, base, mix(seed ^ position) as u32);
+        res += digit as f64 * scale;
+        scale *= inverse_base;
+        index /= base;
+        position += 1;
+    }
+    (res as f32).min(ONE_MINUS_EPSILON)
+}
+
+/// Second dimension of the Sobol sequence as bits of a fraction, from the
+/// primitive polynomial x + 1.
+fn sobol_second(mut index: u32) -> u32 {
+    let mut v = 1u32 << 31;
+    let mut res = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            res ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    res
+}
+
+/// Owen scrambling of the bits of a fraction: flipping each bit depending on
+/// the ones above it, which keeps stratification intact.
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Hash where every bit only depends on the bits below it.
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [SamplerType; 4] = [
+        SamplerType::Independent,
+        SamplerType::Stratified,
+        SamplerType::Halton,
+        SamplerType::Sobol,
+    ];
+
+    #[test]
+    fn permutation() {
+        for &n in [1, 5, 16, 100].iter() {
+            let mut seen: Vec<u32> = (0..n).map(|i| permute(i, n, 12345)).collect();
+            seen.sort();
+            assert_eq!((0..n).collect::<Vec<u32>>(), seen);
+        }
+    }
+
+    #[test]
+    fn sobol_second_dimension() {
+        let points: Vec<f32> = (0..4).map(|i| to_unit(sobol_second(i))).collect();
+        assert_eq!(vec![0.0, 0.5, 0.75, 0.25], points);
+    }
+
+    #[test]
+    fn in_unit_interval() {
+        for kind in ALL.iter() {
+            let mut sampler = kind.create(3, 4, 16);
+            for i in 0..16 {
+                sampler.start_pixel_sample(7, 2, i);
+                for _ in 0..40 {
+                    let u = sampler.get_1d();
+                    let (a, b) = sampler.get_2d();
+                    for v in [u, a, b] {
+                        assert!((0.0..1.0).contains(&v), "{}", v);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Number of the `n` samples in each of `n` equal bins per dimension of
+    /// pixel (`x`, `y`), for the given dimension.
+    fn strata(sampler: &mut dyn Sampler, x: usize, y: usize, n: u32, dimension: u32) -> Vec<u32> {
+        let mut bins = vec![0; n as usize];
+        for i in 0..n {
+            sampler.start_pixel_sample(x, y, i);
+            sampler.set_dimension(dimension);
+            bins[(sampler.get_1d() * n as f32) as usize] += 1;
+        }
+        bins
+    }
+
+    #[test]
+    fn stratified_dimensions() {
+        // Each sample in its own stratum, in every dimension and pixel.
+        for kind in [SamplerType::Stratified, SamplerType::Sobol] {
+            let mut sampler = kind.create(9, 0, 16);
+            for dimension in [0, 1, 7, 60] {
+                for (x, y) in [(0, 0), (3, 5)] {
+                    let bins = strata(sampler.as_mut(), x, y, 16, dimension);
+                    assert!(bins.iter().all(|&b| b == 1), "{:?}", bins);
+                }
+            }
+        }
+        // The Halton sequence for powers of each dimension's base.
+        let mut halton = Halton::new(9);
+        let bins = strata(&mut halton, 1, 1, 16, 0);
+        assert!(bins.iter().all(|&b| b == 1), "{:?}", bins);
+        let bins = strata(&mut halton, 1, 1, 9, 1);
+        assert!(bins.iter().all(|&b| b == 1), "{:?}", bins);
+    }
+
+    #[test]
+    fn pairs_cover_the_square() {
+        // 16 samples in a 4x4 grid, one per cell.
+        for kind in [SamplerType::Stratified, SamplerType::Sobol] {
+            let mut sampler = kind.create(2, 0, 16);
+            let mut cells = [0; 16];
+            for i in 0..16 {
+                sampler.start_pixel_sample(4, 4, i);
+                sampler.set_dimension(5);
+                let (a, b) = sampler.get_2d();
+                cells[(a * 4.0) as usize * 4 + (b * 4.0) as usize] += 1;
+            }
+            assert!(cells.iter().all(|&c| c == 1), "{:?}", cells);
+        }
+    }
+
+    #[test]
+    fn pixels_differ() {
+        for kind in ALL.iter().skip(1) {
+            let mut sampler = kind.create(1, 0, 8);
+            sampler.start_pixel_sample(0, 0, 3);
+            let a = sampler.get_1d();
+            sampler.start_pixel_sample(1, 0, 3);
+            let b = sampler.get_1d();
+            assert!(a != b);
+            // And the same pixel sample gives the same numbers again.
+            sampler.start_pixel_sample(0, 0, 3);
+            assert_eq!(a, sampler.get_1d());
+        }
+    }
+
+    #[test]
+    fn integrates() {
+        // Mean of x * y over the unit square, 1/4.
+        for kind in ALL.iter() {
+            let mut sampler = kind.create(5, 6, 64);
+            let mut sum = 0.0;
+            for pixel in 0..50 {
+                for i in 0..64 {
+                    sampler.start_pixel_sample(pixel, 0, i);
+                    let (a, b) = sampler.get_2d();
+                    sum += (a * b) as f64;
+                }
+            }
+            let mean = sum / (50.0 * 64.0);
+            assert!((mean - 0.25).abs() < 0.005, "{}", mean);
+        }
+    }
+}
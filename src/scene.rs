@@ -1,8 +1,21 @@
-use crate::material::Material;
+use crate::bvh::Bvh;
+use crate::environment::Environment;
+use crate::light::Light;
+use crate::material::{Color, Material};
 use crate::math::*;
 
 pub struct Scene {
     pub objs: Vec<Object>,
+    // Point-like lights, only found by direct light sampling.
+    pub lights: Vec<Light>,
+    // Seen by rays leaving the scene.
+    pub environment: Environment,
+    bvh: Bvh,
+    // Objects without bounds, like planes, are tested against every ray.
+    unbounded: Vec<usize>,
+    // Emissive objects with bounds, candidates for direct light sampling.
+    // Unbounded ones can't be sampled, they are only found by BSDF samples.
+    emitters: Vec<usize>,
 }
 
 pub struct Object {
@@ -10,9 +23,78 @@ pub struct Object {
     pub material: Material,
 }
 
+impl Default for Scene {
+    fn default() -> Scene {
+        Scene {
+            objs: Vec::new(),
+            lights: Vec::new(),
+            environment: Environment::Constant(Color {
+                red: 1.0,
+                green: 1.0,
+                blue: 1.0,
+            }),
+            bvh: Bvh::empty(),
+            unbounded: Vec::new(),
+            emitters: Vec::new(),
+        }
+    }
+}
+
 impl Scene {
     pub fn new() -> Scene {
-        Scene { objs: Vec::new() }
+        Scene::default()
+    }
+
+    /// Builds the acceleration structure, must be called after the last
+    /// object is added.
+    pub fn build(&mut self) {
+        let (bounded, unbounded): (Vec<usize>, Vec<usize>) =
+            (0..self.objs.len()).partition(|&i| self.objs[i].shape.bounds().is_some());
+        self.bvh = Bvh::build(&self.objs, &bounded);
+        self.unbounded = unbounded;
+        self.emitters = (0..self.objs.len())
+            .filter(|&i| self.objs[i].material.is_emissive())
+            .filter(|&i| self.objs[i].shape.bounds().is_some())
+            .collect();
+    }
+
+    pub fn emitters(&self) -> &[usize] {
+        &self.emitters
+    }
+
+    /// Number of things direct light sampling picks from uniformly: the
+    /// `emitters`, the `lights`, then the environment if it is sampled.
+    pub fn light_count(&self) -> usize {
+        self.emitters.len() + self.lights.len() + self.environment.is_sampled() as usize
+    }
+
+    /// Density of direct light sampling picking `light` and then `direction`
+    /// from `from`.
+    pub fn light_pdf(&self, light: &Object, from: Point, direction: Vector) -> f32 {
+        light.shape.pdf_towards(from, direction) / self.light_count() as f32
+    }
+
+    /// Density of direct light sampling picking the environment and then
+    /// `direction`.
+    pub fn environment_pdf(&self, direction: Vector) -> f32 {
+        if !self.environment.is_sampled() {
+            return 0.0;
+        }
+        self.environment.pdf(direction) / self.light_count() as f32
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> Option<(&Object, Intersection)> {
+        let mut closest: Option<(&Object, Intersection)> = None;
+        for &i in self.unbounded.iter() {
+            let obj = &self.objs[i];
+            if let Some(intersection) = obj.shape.intersect(ray) {
+                if closest.is_none() || intersection.distance < closest.unwrap().1.distance {
+                    closest = Some((obj, intersection));
+                }
+            }
+        }
+        let t_max = closest.map_or(f32::INFINITY, |(_, c)| c.distance);
+        self.bvh.intersect(&self.objs, ray, t_max).or(closest)
     }
 }
 
@@ -20,8 +102,9 @@ pub struct Camera {
     pub look_from: Point,
     pub direction: Vector,
     pub up: Vector,
+    // Horizontal field of view, the vertical one follows from the image's
+    // aspect ratio.
     pub fov: f32,
-    pub aspect: f32,
     pub aperture: f32,
     pub focal_distance: f32,
 }
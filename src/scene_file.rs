@@ -0,0 +1,1337 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::bump;
+use crate::environment;
+use crate::light;
+use crate::material;
+use crate::math::*;
+use crate::obj;
+use crate::scene;
+use crate::sky;
+use crate::texture;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: CameraDesc,
+    background: Option<BackgroundDesc>,
+    #[serde(default)]
+    materials: BTreeMap<String, MaterialDesc>,
+    #[serde(default)]
+    sphere: Vec<SphereDesc>,
+    #[serde(default)]
+    plane: Vec<PlaneDesc>,
+    #[serde(default)]
+    mesh: Vec<MeshDesc>,
+    #[serde(default)]
+    point_light: Vec<PointLightDesc>,
+    #[serde(default)]
+    spot_light: Vec<SpotLightDesc>,
+    #[serde(default)]
+    directional_light: Vec<DirectionalLightDesc>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CameraDesc {
+    look_from: [f32; 3],
+    direction: Spanned<[f32; 3]>,
+    up: Spanned<[f32; 3]>,
+    fov: f32,
+    aperture: f32,
+    focal_distance: f32,
+}
+
+// What rays leaving the scene see, white by default.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackgroundDesc {
+    color: Option<[f32; 3]>,
+    // Equirectangular image, relative to the directory of the scene file.
+    image: Option<Spanned<PathBuf>>,
+    sky: Option<Spanned<SkyDesc>>,
+    // Turn of the image around the up (z) axis, in degrees.
+    #[serde(default)]
+    rotation: f32,
+    #[serde(default = "default_intensity")]
+    intensity: f32,
+}
+
+// Daylight with the sun, angles in degrees.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SkyDesc {
+    sun_elevation: f32,
+    // Around the up (z) axis, from +x towards +y.
+    #[serde(default)]
+    sun_azimuth: f32,
+    #[serde(default = "default_turbidity")]
+    turbidity: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaterialDesc {
+    diffuse: Option<Spanned<TextureDesc>>,
+    emissive: Option<Spanned<TextureDesc>>,
+    ior: Option<Spanned<f32>>,
+    #[serde(default)]
+    transparency: f32,
+    // Fraction of light left after travelling `transmittance_distance` inside
+    // a transparent material.
+    transmittance: Option<Spanned<[f32; 3]>>,
+    transmittance_distance: Option<Spanned<f32>>,
+    // Wavelength dependent index of refraction of transparent materials,
+    // replacing `ior`.
+    cauchy: Option<Spanned<CauchyDesc>>,
+    sellmeier: Option<Spanned<SellmeierDesc>>,
+    // Roughness of the reflections, from 0 (mirror) to 1.
+    roughness: Option<Spanned<f32>>,
+    // Makes the material a metal, either one of `material::METALS` by name or
+    // given by its complex index of refraction.
+    metal: Option<Spanned<String>>,
+    eta: Option<Spanned<[f32; 3]>>,
+    k: Option<Spanned<[f32; 3]>>,
+    // Tangent space normal map, or height field scaled by `bump_scale`.
+    normal_map: Option<Spanned<TextureDesc>>,
+    bump: Option<Spanned<TextureDesc>>,
+    #[serde(default = "default_bump_scale")]
+    bump_scale: f32,
+}
+
+// Either a plain color or a table describing a texture.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TextureDesc {
+    Color([f32; 3]),
+    Image(ImageDesc),
+    Procedural(ProceduralDesc),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ImageDesc {
+    // Relative to the directory of the scene file.
+    image: PathBuf,
+    #[serde(default = "default_wrap")]
+    wrap: texture::Wrap,
+}
+
+// Solid texture blending between two colors.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProceduralDesc {
+    pattern: PatternDesc,
+    colors: [[f32; 3]; 2],
+    #[serde(default = "default_scale")]
+    scale: f32,
+    #[serde(default = "default_space")]
+    space: texture::Space,
+    #[serde(default = "default_octaves")]
+    octaves: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum PatternDesc {
+    Checker,
+    Noise,
+    Turbulence,
+    Marble,
+    Wood,
+}
+
+// Coefficients for wavelengths in micrometers.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CauchyDesc {
+    a: f32,
+    b: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SellmeierDesc {
+    b: [f32; 3],
+    c: [f32; 3],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SphereDesc {
+    center: [f32; 3],
+    radius: Spanned<f32>,
+    material: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PlaneDesc {
+    point: [f32; 3],
+    normal: Spanned<[f32; 3]>,
+    material: Spanned<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PointLightDesc {
+    position: [f32; 3],
+    // Irradiance at unit distance.
+    intensity: [f32; 3],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SpotLightDesc {
+    position: [f32; 3],
+    direction: [f32; 3],
+    intensity: [f32; 3],
+    // Half angle of the cone, in degrees.
+    angle: Spanned<f32>,
+    // Degrees at the edge of the cone over which the light fades out, five
+    // by default.
+    falloff: Option<Spanned<f32>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DirectionalLightDesc {
+    // Where the light travels, not where it comes from.
+    direction: [f32; 3],
+    irradiance: [f32; 3],
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MeshDesc {
+    // Relative to the directory of the scene file.
+    path: PathBuf,
+    // Overrides the materials from the mesh's own material library.
+    material: Option<Spanned<String>>,
+}
+
+fn default_intensity() -> f32 {
+    1.0
+}
+
+fn default_turbidity() -> f32 {
+    3.0
+}
+
+fn default_bump_scale() -> f32 {
+    1.0
+}
+
+fn default_wrap() -> texture::Wrap {
+    texture::Wrap::Repeat
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_space() -> texture::Space {
+    texture::Space::Object
+}
+
+fn default_octaves() -> u32 {
+    6
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        key: Option<String>,
+        message: String,
+    },
+    Mesh(obj::Error),
+    Texture {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Parse {
+                path,
+                line,
+                column,
+                key,
+                message,
+            } => {
+                write!(f, "{}:{}:{}: ", path.display(), line, column)?;
+                if let Some(key) = key {
+                    write!(f, "`{}`: ", key)?;
+                }
+                write!(f, "{}", message)
+            }
+            Error::Mesh(e) => e.fmt(f),
+            Error::Texture { path, source } => write!(f, "{}: {}", path.display(), source),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub fn load(path: &Path) -> Result<(scene::Scene, scene::Camera), Error> {
+    let source = fs::read_to_string(path).map_err(|source| Error::Io {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    parse(&source, path)
+}
+
+pub fn parse(source: &str, path: &Path) -> Result<(scene::Scene, scene::Camera), Error> {
+    let desc: SceneDesc = toml::from_str(source).map_err(|e| {
+        let span = e.span().unwrap_or(0..0);
+        parse_error(source, path, span, e.message())
+    })?;
+
+    let c = &desc.camera;
+    let direction = nonzero(source, path, &c.direction, "direction")?;
+    let up = nonzero(source, path, &c.up, "up")?;
+    let across = cross(direction, up).square_length();
+    if across <= 1e-12 * direction.square_length() * up.square_length() {
+        return Err(parse_error(
+            source,
+            path,
+            c.up.span(),
+            "up must not be parallel to direction",
+        ));
+    }
+    let camera = scene::Camera {
+        look_from: point(c.look_from),
+        direction: direction.normalize(),
+        up,
+        fov: c.fov,
+        aperture: c.aperture,
+        focal_distance: c.focal_distance,
+    };
+
+    // Built once up front so textures are shared by all objects using them.
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut materials = HashMap::new();
+    for (name, m) in desc.materials.iter() {
+        materials.insert(name.as_str(), build_material(source, path, dir, m)?);
+    }
+    let material = |name: &Spanned<String>| -> Result<material::Material, Error> {
+        match materials.get(name.get_ref().as_str()) {
+            Some(m) => Ok(m.clone()),
+            None => Err(parse_error(
+                source,
+                path,
+                name.span(),
+                &format!("unknown material `{}`", name.get_ref()),
+            )),
+        }
+    };
+
+    let mut scene = scene::Scene::new();
+    if let Some(b) = &desc.background {
+        scene.environment = build_environment(source, path, dir, b)?;
+    }
+    for p in desc.plane.iter() {
+        scene.objs.push(scene::Object {
+            shape: Box::new(Plane {
+                point: point(p.point),
+                normal: nonzero(source, path, &p.normal, "normal")?.normalize(),
+            }),
+            material: material(&p.material)?,
+        });
+    }
+    for s in desc.sphere.iter() {
+        if *s.radius.get_ref() <= 0.0 {
+            return Err(parse_error(
+                source,
+                path,
+                s.radius.span(),
+                "radius must be positive",
+            ));
+        }
+        scene.objs.push(scene::Object {
+            shape: Box::new(Sphere {
+                center: point(s.center),
+                radius: *s.radius.get_ref(),
+            }),
+            material: material(&s.material)?,
+        });
+    }
+    for m in desc.mesh.iter() {
+        let override_material = match &m.material {
+            Some(name) => Some(material(name)?),
+            None => None,
+        };
+        let mesh_path = dir.join(&m.path);
+        let objs = obj::load(&mesh_path, override_material.as_ref()).map_err(Error::Mesh)?;
+        scene.objs.extend(objs);
+    }
+    for l in desc.point_light.iter() {
+        scene.lights.push(light::Light::Point {
+            position: point(l.position),
+            intensity: color(l.intensity),
+        });
+    }
+    for l in desc.spot_light.iter() {
+        let angle = *l.angle.get_ref();
+        if angle <= 0.0 || angle > 180.0 {
+            return Err(parse_error(
+                source,
+                path,
+                l.angle.span(),
+                "angle must be between 0 and 180 degrees",
+            ));
+        }
+        let falloff = match &l.falloff {
+            Some(f) if *f.get_ref() < 0.0 || *f.get_ref() > angle => {
+                return Err(parse_error(
+                    source,
+                    path,
+                    f.span(),
+                    "falloff must be between 0 and the angle",
+                ))
+            }
+            Some(f) => *f.get_ref(),
+            None => angle.min(5.0),
+        };
+        scene.lights.push(light::Light::spot(
+            point(l.position),
+            vector(l.direction),
+            color(l.intensity),
+            angle.to_radians(),
+            falloff.to_radians(),
+        ));
+    }
+    for l in desc.directional_light.iter() {
+        scene.lights.push(light::Light::directional(
+            vector(l.direction),
+            color(l.irradiance),
+        ));
+    }
+    scene.build();
+    Ok((scene, camera))
+}
+
+fn build_material(
+    source: &str,
+    path: &Path,
+    dir: &Path,
+    m: &MaterialDesc,
+) -> Result<material::Material, Error> {
+    let emissive = match &m.emissive {
+        Some(t) => match t.get_ref() {
+            TextureDesc::Color(c) if *c == [0.0; 3] => None,
+            _ => Some(build_texture(source, path, dir, t, true)?),
+        },
+        None => None,
+    };
+    let diffuse: Arc<dyn texture::Texture> = match &m.diffuse {
+        Some(t) => build_texture(source, path, dir, t, true)?,
+        None => Arc::new(color([0.0; 3])),
+    };
+    let bump = match (&m.normal_map, &m.bump) {
+        (Some(n), None) => Some(bump::Bump::Normal(build_texture(
+            source, path, dir, n, false,
+        )?)),
+        (None, Some(b)) => Some(bump::Bump::Height {
+            texture: build_texture(source, path, dir, b, false)?,
+            scale: m.bump_scale,
+        }),
+        (Some(_), Some(b)) => {
+            return Err(parse_error(
+                source,
+                path,
+                b.span(),
+                "only one of `normal_map` and `bump` can be given",
+            ))
+        }
+        (None, None) => None,
+    };
+    let roughness = match &m.roughness {
+        Some(r) => {
+            let value = *r.get_ref();
+            if !(0.0..=1.0).contains(&value) {
+                return Err(parse_error(
+                    source,
+                    path,
+                    r.span(),
+                    "roughness must be between 0 and 1",
+                ));
+            }
+            if m.transparency > 0.0 && value > 0.0 {
+                return Err(parse_error(
+                    source,
+                    path,
+                    r.span(),
+                    "transparent materials can't be rough",
+                ));
+            }
+            value
+        }
+        None => 0.0,
+    };
+    let conductor = match (&m.metal, &m.eta) {
+        (Some(name), None) => match material::Conductor::named(name.get_ref()) {
+            Some(c) => Some(c),
+            None => {
+                let names: Vec<&str> = material::METALS.iter().map(|(n, _)| *n).collect();
+                return Err(parse_error(
+                    source,
+                    path,
+                    name.span(),
+                    &format!(
+                        "unknown metal `{}`, expected one of {}",
+                        name.get_ref(),
+                        names.join(", ")
+                    ),
+                ));
+            }
+        },
+        (None, Some(eta)) => Some(material::Conductor {
+            eta: color(*eta.get_ref()),
+            k: color(m.k.as_ref().map_or([0.0; 3], |k| *k.get_ref())),
+        }),
+        (Some(name), Some(_)) => {
+            return Err(parse_error(
+                source,
+                path,
+                name.span(),
+                "a metal can't also have `eta`",
+            ))
+        }
+        (None, None) => match &m.k {
+            Some(k) => return Err(parse_error(source, path, k.span(), "`k` needs `eta`")),
+            None => None,
+        },
+    };
+    if let (Some(_), Some(d)) = (&conductor, &m.diffuse) {
+        return Err(parse_error(
+            source,
+            path,
+            d.span(),
+            "metals can't have a diffuse color",
+        ));
+    }
+    if let (None, Some(d)) = (&m.transmittance, &m.transmittance_distance) {
+        return Err(parse_error(
+            source,
+            path,
+            d.span(),
+            "`transmittance_distance` needs `transmittance`",
+        ));
+    }
+    let dispersion = match (&m.cauchy, &m.sellmeier) {
+        (Some(c), None) => Some((
+            material::Dispersion::Cauchy {
+                a: c.get_ref().a,
+                b: c.get_ref().b,
+            },
+            c.span(),
+        )),
+        (None, Some(s)) => Some((
+            material::Dispersion::Sellmeier {
+                b: s.get_ref().b,
+                c: s.get_ref().c,
+            },
+            s.span(),
+        )),
+        (Some(_), Some(s)) => {
+            return Err(parse_error(
+                source,
+                path,
+                s.span(),
+                "only one of `cauchy` and `sellmeier` can be given",
+            ))
+        }
+        (None, None) => None,
+    };
+    if let (Some(i), Some(_)) = (&m.ior, &dispersion) {
+        return Err(parse_error(
+            source,
+            path,
+            i.span(),
+            "`ior` can't be given with `cauchy` or `sellmeier`",
+        ));
+    }
+    let ior = m.ior.as_ref().map_or(1.0, |i| *i.get_ref());
+    let clear = m
+        .transmittance
+        .as_ref()
+        .map(|t| t.span())
+        .or_else(|| dispersion.as_ref().map(|(_, span)| span.clone()));
+    let res = if let Some(emissive) = emissive {
+        material::Material::create_emissive(emissive)
+    } else if let Some(conductor) = conductor {
+        material::Material::create_conductor(conductor, roughness)
+    } else if let Some(span) = clear {
+        if m.transparency != 1.0 {
+            return Err(parse_error(
+                source,
+                path,
+                span,
+                "only fully transparent materials can absorb or disperse",
+            ));
+        }
+        let distance = match &m.transmittance_distance {
+            Some(d) if *d.get_ref() <= 0.0 => {
+                return Err(parse_error(
+                    source,
+                    path,
+                    d.span(),
+                    "`transmittance_distance` must be positive",
+                ));
+            }
+            Some(d) => *d.get_ref(),
+            None => 1.0,
+        };
+        let transmittance = m.transmittance.as_ref().map_or([1.0; 3], |t| *t.get_ref());
+        match dispersion {
+            Some((dispersion, _)) => {
+                material::Material::create_dispersive(dispersion, color(transmittance), distance)
+            }
+            None => material::Material::create_dielectric(ior, color(transmittance), distance),
+        }
+    } else if roughness > 0.0 {
+        material::Material::create_glossy(diffuse, ior, roughness)
+    } else {
+        material::Material::create(diffuse, ior, m.transparency)
+    };
+    Ok(match bump {
+        Some(bump) => res.with_bump(bump),
+        None => res,
+    })
+}
+
+fn build_environment(
+    source: &str,
+    path: &Path,
+    dir: &Path,
+    b: &BackgroundDesc,
+) -> Result<environment::Environment, Error> {
+    let given = [b.color.is_some(), b.image.is_some(), b.sky.is_some()];
+    if given.iter().filter(|&&g| g).count() > 1 {
+        // `color` has no span to point at.
+        let span = match (&b.image, &b.sky) {
+            (Some(image), None) => image.span(),
+            (_, Some(sky)) => sky.span(),
+            (None, None) => unreachable!(),
+        };
+        return Err(parse_error(
+            source,
+            path,
+            span,
+            "only one of `color`, `image` and `sky` can be given",
+        ));
+    }
+    if let Some(image) = &b.image {
+        let image_path = dir.join(image.get_ref());
+        let map = match texture::ImageTexture::load(&image_path, texture::Wrap::Clamp) {
+            Ok(map) => map,
+            Err(e) => {
+                return Err(Error::Texture {
+                    path: image_path,
+                    source: e,
+                })
+            }
+        };
+        return Ok(environment::Environment::Map(
+            environment::EnvironmentMap::new(map, b.intensity, b.rotation.to_radians()),
+        ));
+    }
+    if let Some(sky) = &b.sky {
+        let s = sky.get_ref();
+        let message = if !(0.0..=90.0).contains(&s.sun_elevation) {
+            Some("`sun_elevation` must be between 0 and 90 degrees")
+        } else if !(1.7..=10.0).contains(&s.turbidity) {
+            Some("`turbidity` must be between 1.7 and 10")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            return Err(parse_error(source, path, sky.span(), message));
+        }
+        return Ok(environment::Environment::Sky(sky::Sky::new(
+            s.sun_elevation.to_radians(),
+            s.sun_azimuth.to_radians(),
+            s.turbidity,
+            b.intensity,
+        )));
+    }
+    Ok(environment::Environment::Constant(
+        color(b.color.unwrap_or([1.0; 3])) * b.intensity,
+    ))
+}
+
+fn build_texture(
+    source: &str,
+    path: &Path,
+    dir: &Path,
+    t: &Spanned<TextureDesc>,
+    // Whether images hold sRGB encoded colors, as opposed to data.
+    srgb: bool,
+) -> Result<Arc<dyn texture::Texture>, Error> {
+    Ok(match t.get_ref() {
+        TextureDesc::Color(c) => Arc::new(color(*c)),
+        TextureDesc::Image(i) => {
+            let image_path = dir.join(&i.image);
+            let image = if srgb {
+                texture::ImageTexture::load(&image_path, i.wrap)
+            } else {
+                texture::ImageTexture::load_linear(&image_path, i.wrap)
+            };
+            match image {
+                Ok(image) => Arc::new(image),
+                Err(e) => {
+                    return Err(Error::Texture {
+                        path: image_path,
+                        source: e,
+                    })
+                }
+            }
+        }
+        TextureDesc::Procedural(p) => {
+            if p.scale <= 0.0 {
+                return Err(parse_error(
+                    source,
+                    path,
+                    t.span(),
+                    "texture `scale` must be positive",
+                ));
+            }
+            let colors = [color(p.colors[0]), color(p.colors[1])];
+            let placement = texture::Placement {
+                space: p.space,
+                scale: p.scale,
+            };
+            let octaves = p.octaves;
+            match p.pattern {
+                PatternDesc::Checker => Arc::new(texture::Checker { colors, placement }),
+                PatternDesc::Noise | PatternDesc::Turbulence => Arc::new(texture::Noise {
+                    colors,
+                    octaves,
+                    turbulence: matches!(p.pattern, PatternDesc::Turbulence),
+                    placement,
+                }),
+                PatternDesc::Marble => Arc::new(texture::Marble {
+                    colors,
+                    octaves,
+                    placement,
+                }),
+                PatternDesc::Wood => Arc::new(texture::Wood {
+                    colors,
+                    octaves,
+                    placement,
+                }),
+            }
+        }
+    })
+}
+
+fn parse_error(source: &str, path: &Path, span: Range<usize>, message: &str) -> Error {
+    let start = span.start.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line = source[..start].matches('\n').count() + 1;
+    let column = source[line_start..start].chars().count() + 1;
+
+    // Serde names the offending field in messages like "missing field `radius`",
+    // otherwise the key is whatever precedes the `=` on the offending line.
+    let key = field_in_message(message).or_else(|| {
+        let before = &source[line_start..start];
+        before
+            .find('=')
+            .map(|i| before[..i].trim().to_string())
+            .filter(|k| !k.is_empty())
+    });
+    Error::Parse {
+        path: path.to_path_buf(),
+        line,
+        column,
+        key,
+        message: message.trim_end().to_string(),
+    }
+}
+
+/// The vector at `v`, which has to have a direction.
+fn nonzero(source: &str, path: &Path, v: &Spanned<[f32; 3]>, key: &str) -> Result<Vector, Error> {
+    let vector = vector(*v.get_ref());
+    let length = vector.square_length();
+    if length > 0.0 && length.is_finite() {
+        Ok(vector)
+    } else {
+        Err(parse_error(
+            source,
+            path,
+            v.span(),
+            &format!("{} must not be zero", key),
+        ))
+    }
+}
+
+fn field_in_message(message: &str) -> Option<String> {
+    let rest = &message[message.find("field `")? + "field `".len()..];
+    Some(rest[..rest.find('`')?].to_string())
+}
+
+fn point(p: [f32; 3]) -> Point {
+    Point {
+        x: p[0],
+        y: p[1],
+        z: p[2],
+    }
+}
+
+fn vector(v: [f32; 3]) -> Vector {
+    Vector {
+        x: v[0],
+        y: v[1],
+        z: v[2],
+    }
+}
+
+fn color(c: [f32; 3]) -> material::Color {
+    material::Color {
+        red: c[0],
+        green: c[1],
+        blue: c[2],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "
+[camera]
+look_from = [0.0, -10.0, 1.0]
+direction = [0.0, 1.0, 0.0]
+up = [0.0, 0.0, 1.0]
+fov = 0.785
+aperture = 0.0
+focal_distance = 10.0
+";
+
+    fn parse_str(source: &str) -> Result<(scene::Scene, scene::Camera), Error> {
+        parse(source, Path::new("test.toml"))
+    }
+
+    #[test]
+    fn parse_default_scene() {
+        let (scene, _) = parse_str(include_str!("../scenes/default.toml")).unwrap();
+        assert_eq!(12, scene.objs.len());
+    }
+
+    #[test]
+    fn parse_objects() {
+        let source = format!(
+            "{}
+[materials.white]
+diffuse = [1.0, 1.0, 1.0]
+
+[[plane]]
+point = [0.0, 0.0, 0.0]
+normal = [0.0, 0.0, 1.0]
+material = \"white\"
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"white\"
+",
+            CAMERA
+        );
+        let (scene, camera) = parse_str(&source).unwrap();
+        assert_eq!(2, scene.objs.len());
+        assert_eq!(1.0, camera.direction.y);
+    }
+
+    #[test]
+    fn parse_error_reports_line_and_key() {
+        let source = format!(
+            "{}
+[materials.white]
+diffuse = [1.0, 1.0, 1.0]
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = \"big\"
+material = \"white\"
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(15, line);
+                assert_eq!(Some("radius".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_unknown_material() {
+        let source = format!(
+            "{}
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"missing\"
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(13, line);
+                assert_eq!(Some("material".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_degenerate_vectors() {
+        let parallel = CAMERA.replace("up = [0.0, 0.0, 1.0]", "up = [0.0, 2.0, 0.0]");
+        let zero = CAMERA.replace("direction = [0.0, 1.0, 0.0]", "direction = [0.0, 0.0, 0.0]");
+        let flat = format!(
+            "{}
+[materials.white]
+diffuse = [1.0, 1.0, 1.0]
+
+[[plane]]
+point = [0.0, 0.0, 0.0]
+normal = [0.0, 0.0, 0.0]
+material = \"white\"
+",
+            CAMERA
+        );
+        for (source, line, expected) in [
+            (parallel, 5, "up"),
+            (zero, 4, "direction"),
+            (flat, 15, "normal"),
+        ] {
+            match parse_str(&source) {
+                Err(Error::Parse { line: l, key, .. }) => {
+                    assert_eq!(line, l);
+                    assert_eq!(Some(expected.to_string()), key);
+                }
+                _ => panic!("expected parse error"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_error_missing_field() {
+        let source = format!(
+            "{}
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+material = \"white\"
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { key, .. }) => assert_eq!(Some("radius".to_string()), key),
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_rough_glass() {
+        let source = format!(
+            "{}
+[materials.glass]
+ior = 1.5
+transparency = 1.0
+roughness = 0.2
+",
+            CAMERA
+        );
+        let source = format!(
+            "{}
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"glass\"
+",
+            source
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(13, line);
+                assert_eq!(Some("roughness".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_metals() {
+        let source = format!(
+            "{}
+[materials.gold]
+metal = \"gold\"
+roughness = 0.2
+
+[materials.custom]
+eta = [0.2, 0.9, 1.1]
+k = [3.9, 2.5, 2.1]
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"gold\"
+
+[[sphere]]
+center = [0.0, 0.0, 3.0]
+radius = 1.0
+material = \"custom\"
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        assert_eq!(2, scene.objs.len());
+
+        let source = source.replace("metal = \"gold\"", "metal = \"mithril\"");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(11, line);
+                assert_eq!(Some("metal".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_image_texture() {
+        let source = format!(
+            "{}
+[materials.wood]
+diffuse = {{ image = \"missing.png\", wrap = \"mirror\" }}
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Texture { path, .. }) => assert_eq!(Path::new("missing.png"), path),
+            _ => panic!("expected texture error"),
+        }
+
+        let source = source.replace("mirror", "sideways");
+        assert!(matches!(
+            parse_str(&source),
+            Err(Error::Parse { line: 11, .. })
+        ));
+    }
+
+    #[test]
+    fn parse_procedural_textures() {
+        let source = format!(
+            "{}
+[materials.floor]
+diffuse = {{ pattern = \"checker\", colors = [[0.8, 0.8, 0.8], [0.3, 0.3, 0.3]], space = \"world\" }}
+
+[materials.stone]
+diffuse = {{ pattern = \"marble\", colors = [[0.9, 0.9, 0.9], [0.2, 0.2, 0.3]], scale = 0.5 }}
+
+[materials.lava]
+emissive = {{ pattern = \"turbulence\", colors = [[0.0, 0.0, 0.0], [4.0, 1.0, 0.0]], octaves = 3 }}
+
+[[plane]]
+point = [0.0, 0.0, 0.0]
+normal = [0.0, 0.0, 1.0]
+material = \"floor\"
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"stone\"
+
+[[sphere]]
+center = [0.0, 0.0, 3.0]
+radius = 1.0
+material = \"lava\"
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        assert_eq!(3, scene.objs.len());
+        assert!(scene.objs[2].material.is_emissive());
+
+        let source = source.replace("scale = 0.5", "scale = 0.0");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(14, line);
+                assert_eq!(Some("diffuse".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_bump() {
+        let source = format!(
+            "{}
+[materials.rough]
+diffuse = [0.8, 0.8, 0.8]
+bump = {{ pattern = \"noise\", colors = [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0]], scale = 0.2 }}
+bump_scale = 0.05
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"rough\"
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        assert_eq!(1, scene.objs.len());
+
+        let source = source.replace("diffuse = [0.8, 0.8, 0.8]", "normal_map = [0.5, 0.5, 1.0]");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(12, line);
+                assert_eq!(Some("bump".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_background() {
+        let source = format!(
+            "{}
+[background]
+color = [0.1, 0.2, 0.3]
+intensity = 2.0
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        let d = Vector {
+            x: 0.0,
+            y: 0.0,
+            z: 1.0,
+        };
+        assert_eq!(0.4, scene.environment.radiance(d).green);
+        assert!(!scene.environment.is_sampled());
+
+        let source = source.replace("intensity", "image = \"sky.hdr\"\nintensity");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(12, line);
+                assert_eq!(Some("image".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_sky() {
+        let source = format!(
+            "{}
+[background]
+sky = {{ sun_elevation = 30.0, sun_azimuth = 90.0 }}
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        assert!(scene.environment.is_sampled());
+        let (d, _) = scene.environment.sample(0.5, 0.5).unwrap();
+        assert!(d.y > 0.86 && d.z > 0.49, "{} {}", d.y, d.z);
+
+        for bad in [
+            "sun_elevation = 95.0",
+            "sun_elevation = 30.0, turbidity = 20.0",
+        ] {
+            let source = format!("{}\n[background]\nsky = {{ {} }}\n", CAMERA, bad);
+            match parse_str(&source) {
+                Err(Error::Parse { line, key, .. }) => {
+                    assert_eq!(11, line);
+                    assert_eq!(Some("sky".to_string()), key);
+                }
+                _ => panic!("expected parse error"),
+            }
+        }
+    }
+
+    #[test]
+    fn parse_lights() {
+        let source = format!(
+            "{}
+[[point_light]]
+position = [0.0, 0.0, 4.0]
+intensity = [16.0, 16.0, 16.0]
+
+[[spot_light]]
+position = [0.0, 0.0, 4.0]
+direction = [0.0, 0.0, -1.0]
+intensity = [10.0, 10.0, 10.0]
+angle = 20.0
+
+[[directional_light]]
+direction = [0.0, 1.0, -1.0]
+irradiance = [2.0, 2.0, 2.0]
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        assert_eq!(3, scene.lights.len());
+        assert_eq!(3, scene.light_count());
+        let origin = Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        };
+        let (_, distance, irradiance) = scene.lights[0].illuminate(origin).unwrap();
+        assert_eq!(4.0, distance);
+        assert_eq!(1.0, irradiance.red);
+
+        let source = source.replace("angle = 20.0", "angle = 20.0\nfalloff = 30.0");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(19, line);
+                assert_eq!(Some("falloff".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_dispersion() {
+        let source = format!(
+            "{}
+[materials.flint]
+transparency = 1.0
+cauchy = {{ a = 1.67, b = 0.0074 }}
+
+[materials.bk7]
+transparency = 1.0
+sellmeier = {{ b = [1.0396, 0.2318, 1.0105], c = [0.0060, 0.0200, 103.56] }}
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"flint\"
+
+[[sphere]]
+center = [0.0, 0.0, 3.0]
+radius = 1.0
+material = \"bk7\"
+",
+            CAMERA
+        );
+        let (scene, _) = parse_str(&source).unwrap();
+        assert_eq!(2, scene.objs.len());
+
+        let source = source.replace("transparency = 1.0\ncauchy", "cauchy");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(11, line);
+                assert_eq!(Some("cauchy".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_diffuse_metal() {
+        let source = format!(
+            "{}
+[materials.gold]
+metal = \"gold\"
+diffuse = [1.0, 1.0, 1.0]
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"gold\"
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(12, line);
+                assert_eq!(Some("diffuse".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_transmittance_distance() {
+        let source = format!(
+            "{}
+[materials.glass]
+ior = 1.5
+transparency = 1.0
+transmittance = [0.5, 0.5, 0.5]
+transmittance_distance = 0.0
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"glass\"
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(14, line);
+                assert_eq!(Some("transmittance_distance".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+
+        // Without a transmittance the distance would be ignored.
+        let source = source.replace("transmittance = [0.5, 0.5, 0.5]\n", "");
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(13, line);
+                assert_eq!(Some("transmittance_distance".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+
+    #[test]
+    fn parse_error_ior_with_dispersion() {
+        let source = format!(
+            "{}
+[materials.flint]
+transparency = 1.0
+ior = 1.6
+cauchy = {{ a = 1.67, b = 0.0074 }}
+
+[[sphere]]
+center = [0.0, 0.0, 1.0]
+radius = 1.0
+material = \"flint\"
+",
+            CAMERA
+        );
+        match parse_str(&source) {
+            Err(Error::Parse { line, key, .. }) => {
+                assert_eq!(12, line);
+                assert_eq!(Some("ior".to_string()), key);
+            }
+            _ => panic!("expected parse error"),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Daylight from the analytic sky model of Preetham, Shirley and Smits
+//! (1999), with the sun as a small bright disk that direct light sampling
+//! can aim for.
+
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use crate::material::Color;
+use crate::math::*;
+use crate::spectrum::xyz_to_rgb;
+
+/// Angular radius of the sun, in radians.
+const SUN_RADIUS: f32 = 0.00465;
+
+/// Illuminance of the sun outside the atmosphere, in lux.
+const SUN_ILLUMINANCE: f32 = 128000.0;
+
+/// Luminance in cd/m^2 to scene radiance, chosen so that the sun overhead,
+/// before the atmosphere dims it, lights a white diffuse surface to a
+/// radiance of one.
+const SCALE: f32 = PI / SUN_ILLUMINANCE;
+
+pub struct Sky {
+    // Unit vector towards the sun, z up.
+    sun: Vector,
+    // Angle between the sun and the zenith.
+    theta_sun: f32,
+    // Luminance and chromaticity overhead.
+    zenith: [f32; 3],
+    // Perez distribution coefficients for Y, x and y.
+    perez: [[f32; 5]; 3],
+    // Radiance of the disk, reddened by its path through the atmosphere.
+    sun_radiance: Color,
+    one_minus_cos_sun: f32,
+    sin2_sun: f32,
+    intensity: f32,
+}
+
+impl Sky {
+    /// Sky with the sun `elevation` above the horizon and `azimuth` around
+    /// the z axis from +x, both in radians, for a turbidity from 1.7 (very
+    /// clear) to 10 (hazy).
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, intensity: f32) -> Sky {
+        let t = turbidity;
+        let theta_sun = (FRAC_PI_2 - elevation).clamp(0.0, FRAC_PI_2);
+        let (sin_e, cos_e) = (FRAC_PI_2 - theta_sun).sin_cos();
+        let sun = Vector {
+            x: cos_e * azimuth.cos(),
+            y: cos_e * azimuth.sin(),
+            z: sin_e,
+        };
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let luminance = ((4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192) * 1000.0;
+        let cubic = |c: [f32; 4]| {
+            let s = theta_sun;
+            c[0] * s * s * s + c[1] * s * s + c[2] * s + c[3]
+        };
+        let chromaticity = |c: [[f32; 4]; 3]| t * t * cubic(c[0]) + t * cubic(c[1]) + cubic(c[2]);
+        let x = chromaticity([
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886],
+        ]);
+        let y = chromaticity([
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688],
+        ]);
+
+        let linear = |c: [[f32; 2]; 5]| c.map(|[a, b]| a * t + b);
+        let perez = [
+            linear([
+                [0.1787, -1.4630],
+                [-0.3554, 0.4275],
+                [-0.0227, 5.3251],
+                [0.1206, -2.5771],
+                [-0.0670, 0.3703],
+            ]),
+            linear([
+                [-0.0193, -0.2592],
+                [-0.0665, 0.0008],
+                [-0.0004, 0.2125],
+                [-0.0641, -0.8989],
+                [-0.0033, 0.0452],
+            ]),
+            linear([
+                [-0.0167, -0.2608],
+                [-0.0950, 0.0092],
+                [-0.0079, 0.2102],
+                [-0.0441, -1.6537],
+                [-0.0109, 0.0529],
+            ]),
+        ];
+
+        let one_minus_cos_sun = 2.0 * (0.5 * SUN_RADIUS).sin().powi(2);
+        let solid_angle = 2.0 * PI * one_minus_cos_sun;
+        let [red, green, blue] =
+            transmittance(theta_sun, t).map(|tr| tr * SUN_ILLUMINANCE * SCALE / solid_angle);
+        Sky {
+            sun,
+            theta_sun,
+            zenith: [luminance.max(0.0), x, y],
+            perez,
+            sun_radiance: Color { red, green, blue },
+            one_minus_cos_sun,
+            sin2_sun: SUN_RADIUS.sin().powi(2),
+            intensity,
+        }
+    }
+
+    pub fn radiance(&self, direction: Vector) -> Color {
+        let cos_gamma = dot(direction, self.sun).clamp(-1.0, 1.0);
+        // Below the horizon sees the sky at the horizon, as if reflected by
+        // the ground.
+        let cos_theta = direction.z.abs().max(1e-3);
+        let gamma = cos_gamma.acos();
+        let [y, cx, cy] = [0, 1, 2].map(|i| {
+            self.zenith[i] * perez(self.perez[i], cos_theta, gamma, cos_gamma)
+                / perez(self.perez[i], 1.0, self.theta_sun, self.theta_sun.cos())
+        });
+        let [red, green, blue] = if cy > 0.0 {
+            xyz_to_rgb([cx / cy * y, y, (1.0 - cx - cy) / cy * y]).map(|c| c.max(0.0) * SCALE)
+        } else {
+            [0.0; 3]
+        };
+        let mut res = Color { red, green, blue };
+        if self.in_sun(direction) {
+            res += self.sun_radiance;
+        }
+        res * self.intensity
+    }
+
+    /// Whether a direction points at the sun, going by the sine of the angle
+    /// between them, which unlike the cosine resolves its small radius.
+    fn in_sun(&self, direction: Vector) -> bool {
+        self.sun.z > 0.0
+            && dot(direction, self.sun) > 0.0
+            && cross(direction, self.sun).square_length() < self.sin2_sun
+    }
+
+    /// Direction towards the sun's disk and its solid angle density.
+    pub fn sample(&self, u1: f32, u2: f32) -> Option<(Vector, f32)> {
+        if self.sun.z <= 0.0 {
+            return None;
+        }
+        let direction = sample_cone(self.sun, self.one_minus_cos_sun, u1, u2);
+        Some((direction, cone_pdf(self.one_minus_cos_sun)))
+    }
+
+    pub fn pdf(&self, direction: Vector) -> f32 {
+        if self.in_sun(direction) {
+            cone_pdf(self.one_minus_cos_sun)
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Perez et al.'s all-weather luminance distribution, relative to a
+/// direction `theta` from the zenith and `gamma` from the sun.
+fn perez(c: [f32; 5], cos_theta: f32, gamma: f32, cos_gamma: f32) -> f32 {
+    let [a, b, c, d, e] = c;
+    (1.0 + a * (b / cos_theta).exp()) * (1.0 + c * (d * gamma).exp() + e * cos_gamma * cos_gamma)
+}
+
+/// Fraction of red, green and blue sunlight making it through the
+/// atmosphere from `theta` off the zenith, losing some to air molecules and
+/// more to haze as the turbidity goes up.
+fn transmittance(theta: f32, turbidity: f32) -> [f32; 3] {
+    // Relative optical air mass, Kasten and Young (1989).
+    let degrees = theta.to_degrees();
+    let air_mass = 1.0 / (theta.cos() + 0.50572 * (96.07995 - degrees).powf(-1.6364));
+    let beta = 0.04608 * turbidity - 0.04586;
+    [0.65, 0.55, 0.45].map(|wavelength: f32| {
+        let rayleigh = 0.008735 * wavelength.powf(-4.08);
+        let aerosol = beta * wavelength.powf(-1.3);
+        (-air_mass * (rayleigh + aerosol)).exp()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+
+    fn direction(elevation: f32, azimuth: f32) -> Vector {
+        let (sin_e, cos_e) = elevation.to_radians().sin_cos();
+        let azimuth = azimuth.to_radians();
+        Vector {
+            x: cos_e * azimuth.cos(),
+            y: cos_e * azimuth.sin(),
+            z: sin_e,
+        }
+    }
+
+    #[test]
+    fn blue_and_brighter_near_the_sun() {
+        let sky = Sky::new(40_f32.to_radians(), 0.0, 3.0, 1.0);
+        let overhead = sky.radiance(direction(90.0, 0.0));
+        assert!(
+            overhead.blue > overhead.red,
+            "{} {}",
+            overhead.red,
+            overhead.blue
+        );
+        let near = sky.radiance(direction(40.0, 10.0));
+        let away = sky.radiance(direction(40.0, 180.0));
+        assert!(
+            near.green > 2.0 * away.green,
+            "{} {}",
+            near.green,
+            away.green
+        );
+        // Far dimmer than the disk itself.
+        assert!(near.green < 0.01 * sky.sun_radiance.green, "{}", near.green);
+    }
+
+    #[test]
+    fn low_sun_is_redder() {
+        let high = Sky::new(60_f32.to_radians(), 0.0, 3.0, 1.0).sun_radiance;
+        let low = Sky::new(3_f32.to_radians(), 0.0, 3.0, 1.0).sun_radiance;
+        assert!(low.red / low.blue > 2.0 * high.red / high.blue);
+        assert!(low.green < high.green);
+    }
+
+    #[test]
+    fn sun_disk_is_sampled() {
+        let sky = Sky::new(30_f32.to_radians(), 1.0, 2.5, 1.0);
+        let mut rng = XorShiftRng::seed_from_u64(4);
+        let mut irradiance = 0.0;
+        let n = 1000;
+        for _ in 0..n {
+            let (d, pdf) = sky.sample(rng.gen(), rng.gen()).unwrap();
+            assert!((sky.pdf(d) - pdf).abs() < 1e-3 * pdf);
+            irradiance += sky.radiance(d).green * d.z / pdf;
+        }
+        // A white floor would reflect one under the sun overhead without
+        // the atmosphere; at 30 degrees half of it lands, less what the air
+        // scatters away on the way.
+        let reflected = irradiance / n as f32 / PI;
+        assert!(reflected > 0.25 && reflected < 0.35, "{}", reflected);
+        assert_eq!(0.0, sky.pdf(direction(80.0, 0.0)));
+        let set = Sky::new(-0.1, 0.0, 2.5, 1.0);
+        assert!(set.sample(0.5, 0.5).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+//! Spectral rendering helpers. In spectral mode the three channels of a
+//! `Color` hold values at three wavelengths instead of red, green and blue:
+//! a randomly sampled hero wavelength followed by two others spread evenly
+//! over the visible range.
+
+use std::sync::OnceLock;
+
+use crate::material::Color;
+
+/// Visible range covered, in nanometers.
+pub const MIN_WAVELENGTH: f32 = 380.0;
+pub const MAX_WAVELENGTH: f32 = 720.0;
+
+/// What the channels of the colors carried along a path stand for.
+#[derive(Copy, Clone)]
+pub enum Channels {
+    Rgb,
+    /// Wavelengths in nanometers, the first one being the hero wavelength.
+    Spectral([f32; 3]),
+    /// Like `Spectral` after the path split up the wavelengths, only the hero
+    /// one is still carried.
+    Hero([f32; 3]),
+}
+
+impl Channels {
+    /// Converts an RGB color, like a reflectance from the scene description,
+    /// to the values of its upsampled spectrum at the path's wavelengths.
+    pub fn color(&self, rgb: Color) -> Color {
+        match self {
+            Channels::Rgb => rgb,
+            Channels::Spectral(wavelengths) | Channels::Hero(wavelengths) => Color {
+                red: upsample(rgb, wavelengths[0]),
+                green: upsample(rgb, wavelengths[1]),
+                blue: upsample(rgb, wavelengths[2]),
+            },
+        }
+    }
+
+    pub fn hero_wavelength(&self) -> Option<f32> {
+        match self {
+            Channels::Rgb => None,
+            Channels::Spectral(wavelengths) | Channels::Hero(wavelengths) => Some(wavelengths[0]),
+        }
+    }
+
+    /// Drops all but the hero wavelength, for when the path takes a
+    /// direction that only makes sense for it, e.g. after dispersion. The
+    /// hero value is scaled to keep the estimate unbiased.
+    pub fn keep_hero(&self, value: Color) -> (Channels, Color) {
+        match self {
+            Channels::Spectral(wavelengths) => (
+                Channels::Hero(*wavelengths),
+                Color {
+                    red: 3.0 * value.red,
+                    green: 0.0,
+                    blue: 0.0,
+                },
+            ),
+            _ => (*self, value),
+        }
+    }
+}
+
+/// Hero wavelength for a uniform random number in [0, 1), plus its rotations.
+pub fn sample_wavelengths(u: f32) -> [f32; 3] {
+    let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+    let mut res = [0.0; 3];
+    for (i, w) in res.iter_mut().enumerate() {
+        let offset = (u + i as f32 / 3.0).fract();
+        *w = MIN_WAVELENGTH + offset * range;
+    }
+    res
+}
+
+/// Converts a radiance sample at the given wavelengths to linear sRGB through
+/// CIE XYZ, balanced so that a flat spectrum maps to white.
+pub fn to_rgb(value: Color, wavelengths: [f32; 3]) -> Color {
+    let values = [value.red, value.green, value.blue];
+    let mut xyz = [0.0; 3];
+    for (v, &w) in values.iter().zip(wavelengths.iter()) {
+        let cmf = color_matching(w);
+        for (c, m) in xyz.iter_mut().zip(cmf.iter()) {
+            *c += v * m;
+        }
+    }
+    let rgb = xyz_to_rgb(xyz);
+    let white = white();
+    Color {
+        red: rgb[0] / white[0],
+        green: rgb[1] / white[1],
+        blue: rgb[2] / white[2],
+    }
+}
+
+/// Linear sRGB of a flat spectrum under the same estimator as `to_rgb`,
+/// i.e. averaged over all wavelength samples.
+fn white() -> [f32; 3] {
+    static WHITE: OnceLock<[f32; 3]> = OnceLock::new();
+    *WHITE.get_or_init(|| {
+        let steps = 1000;
+        let mut xyz = [0.0; 3];
+        for i in 0..steps {
+            let w = MIN_WAVELENGTH
+                + (i as f32 + 0.5) / steps as f32 * (MAX_WAVELENGTH - MIN_WAVELENGTH);
+            for (c, m) in xyz.iter_mut().zip(color_matching(w).iter()) {
+                // Each sample carries three wavelengths.
+                *c += 3.0 * m / steps as f32;
+            }
+        }
+        xyz_to_rgb(xyz)
+    })
+}
+
+/// CIE XYZ to linear sRGB.
+pub fn xyz_to_rgb(xyz: [f32; 3]) -> [f32; 3] {
+    let [x, y, z] = xyz;
+    [
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ]
+}
+
+/// CIE 1931 standard observer, using the multi-lobe fit by Wyman, Sloan and
+/// Shirley (2013).
+pub fn color_matching(wavelength: f32) -> [f32; 3] {
+    let g = |mu: f32, sigma1: f32, sigma2: f32| {
+        let t = (wavelength - mu) / if wavelength < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+    [
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    ]
+}
+
+// Smits' (1999) basis spectra in ten equal bins over the visible range.
+const WHITE: [f32; 10] = [
+    1.0000, 1.0000, 0.9999, 0.9993, 0.9992, 0.9998, 1.0000, 1.0000, 1.0000, 1.0000,
+];
+const CYAN: [f32; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0.0000, 0.0000, 0.0000,
+];
+const MAGENTA: [f32; 10] = [
+    1.0000, 1.0000, 0.9685, 0.2229, 0.0000, 0.0458, 0.8369, 1.0000, 1.0000, 0.9959,
+];
+const YELLOW: [f32; 10] = [
+    0.0001, 0.0000, 0.1088, 0.6651, 1.0000, 1.0000, 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const RED: [f32; 10] = [
+    0.1012, 0.0515, 0.0000, 0.0000, 0.0000, 0.0000, 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const GREEN: [f32; 10] = [
+    0.0000, 0.0000, 0.0273, 0.7937, 1.0000, 0.9418, 0.1719, 0.0000, 0.0000, 0.0025,
+];
+const BLUE: [f32; 10] = [
+    1.0000, 1.0000, 0.8916, 0.3323, 0.0000, 0.0000, 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Value at `wavelength` of a smooth spectrum with the given RGB color,
+/// following Smits: white plus the secondary and primary color making up the
+/// rest.
+pub fn upsample(rgb: Color, wavelength: f32) -> f32 {
+    let bin = ((wavelength - MIN_WAVELENGTH) / (MAX_WAVELENGTH - MIN_WAVELENGTH) * 10.0) as usize;
+    let bin = bin.min(9);
+    let Color { red, green, blue } = rgb;
+    if red <= green && red <= blue {
+        red * WHITE[bin]
+            + if green <= blue {
+                (green - red) * CYAN[bin] + (blue - green) * BLUE[bin]
+            } else {
+                (blue - red) * CYAN[bin] + (green - blue) * GREEN[bin]
+            }
+    } else if green <= red && green <= blue {
+        green * WHITE[bin]
+            + if red <= blue {
+                (red - green) * MAGENTA[bin] + (blue - red) * BLUE[bin]
+            } else {
+                (blue - green) * MAGENTA[bin] + (red - blue) * RED[bin]
+            }
+    } else {
+        blue * WHITE[bin]
+            + if red <= green {
+                (red - blue) * YELLOW[bin] + (green - red) * GREEN[bin]
+            } else {
+                (green - blue) * YELLOW[bin] + (red - green) * RED[bin]
+            }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::prelude::*;
+    use rand_xorshift::XorShiftRng;
+
+    /// Mean linear sRGB of the upsampled `rgb` over many wavelength samples.
+    fn round_trip(rgb: Color) -> Color {
+        let mut rng = XorShiftRng::seed_from_u64(2);
+        let n = 100000;
+        let mut sum = Color {
+            red: 0.0,
+            green: 0.0,
+            blue: 0.0,
+        };
+        for _ in 0..n {
+            let wavelengths = sample_wavelengths(rng.gen());
+            let value = Channels::Spectral(wavelengths).color(rgb);
+            sum += to_rgb(value, wavelengths);
+        }
+        sum * (1.0 / n as f32)
+    }
+
+    #[test]
+    fn white_round_trip() {
+        let white = round_trip(Color {
+            red: 1.0,
+            green: 1.0,
+            blue: 1.0,
+        });
+        for c in [white.red, white.green, white.blue] {
+            assert!((c - 1.0).abs() < 0.01, "{}", c);
+        }
+    }
+
+    #[test]
+    fn primaries_round_trip() {
+        let red = round_trip(Color {
+            red: 0.8,
+            green: 0.1,
+            blue: 0.1,
+        });
+        assert!(red.red > 0.6 && red.green < 0.2 && red.blue < 0.2);
+        let blue = round_trip(Color {
+            red: 0.1,
+            green: 0.1,
+            blue: 0.8,
+        });
+        assert!(blue.blue > 0.6 && blue.red < 0.2 && blue.green < 0.2);
+    }
+
+    #[test]
+    fn wavelengths_in_range() {
+        for &u in [0.0, 0.3, 0.99999].iter() {
+            let wavelengths = sample_wavelengths(u);
+            assert_eq!(
+                MIN_WAVELENGTH + u * (MAX_WAVELENGTH - MIN_WAVELENGTH),
+                wavelengths[0]
+            );
+            for w in wavelengths {
+                assert!((MIN_WAVELENGTH..MAX_WAVELENGTH).contains(&w));
+            }
+        }
+    }
+}
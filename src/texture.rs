@@ -0,0 +1,450 @@
+//! Textures make material colors vary over a surface. They are looked up at
+//! the hit point, mostly through the texture coordinates the shapes report.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use image::codecs::hdr::HdrDecoder;
+use image::{ColorType, ImageError};
+use serde::Deserialize;
+
+use crate::material::Color;
+use crate::math::*;
+use crate::noise;
+
+pub trait Texture: Sync + Send {
+    fn value(&self, hit: &Intersection) -> Color;
+}
+
+/// A plain color is a constant texture.
+impl Texture for Color {
+    fn value(&self, _hit: &Intersection) -> Color {
+        *self
+    }
+}
+
+impl From<Color> for Arc<dyn Texture> {
+    fn from(color: Color) -> Arc<dyn Texture> {
+        Arc::new(color)
+    }
+}
+
+/// Texture computed by a function of the hit.
+pub struct Procedural<F>(pub F);
+
+impl<F: Fn(&Intersection) -> Color + Sync + Send> Texture for Procedural<F> {
+    fn value(&self, hit: &Intersection) -> Color {
+        (self.0)(hit)
+    }
+}
+
+/// Where solid textures are anchored: fixed in the world, or moving along
+/// with the shape they're on.
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Space {
+    World,
+    Object,
+}
+
+/// Puts a solid texture, one defined throughout space, onto surfaces.
+#[derive(Copy, Clone)]
+pub struct Placement {
+    pub space: Space,
+    // Size of the texture's features.
+    pub scale: f32,
+}
+
+impl Placement {
+    fn point(&self, hit: &Intersection) -> Vector {
+        let p = match self.space {
+            Space::World => Vector {
+                x: hit.point.x,
+                y: hit.point.y,
+                z: hit.point.z,
+            },
+            Space::Object => hit.local,
+        };
+        (1.0 / self.scale) * p
+    }
+}
+
+/// Cubes of alternating colors.
+pub struct Checker {
+    pub colors: [Color; 2],
+    pub placement: Placement,
+}
+
+impl Texture for Checker {
+    fn value(&self, hit: &Intersection) -> Color {
+        let p = self.placement.point(hit);
+        // Nudged so surfaces lying on a cell boundary, like a floor at z = 0,
+        // don't flicker between cells because of rounding.
+        let cell = |c: f32| (c + 1e-3).floor() as i64;
+        let parity = (cell(p.x) + cell(p.y) + cell(p.z)).rem_euclid(2);
+        self.colors[parity as usize]
+    }
+}
+
+/// Cloudy blend of two colors, `turbulence` giving billowy creases instead of
+/// smooth variations.
+pub struct Noise {
+    pub colors: [Color; 2],
+    pub octaves: u32,
+    pub turbulence: bool,
+    pub placement: Placement,
+}
+
+impl Texture for Noise {
+    fn value(&self, hit: &Intersection) -> Color {
+        let p = self.placement.point(hit);
+        let t = if self.turbulence {
+            noise::turbulence(p, self.octaves)
+        } else {
+            0.5 + 0.5 * noise::fbm(p, self.octaves)
+        };
+        mix(self.colors, t)
+    }
+}
+
+/// Veins along the x axis, perturbed by turbulence.
+pub struct Marble {
+    pub colors: [Color; 2],
+    pub octaves: u32,
+    pub placement: Placement,
+}
+
+impl Texture for Marble {
+    fn value(&self, hit: &Intersection) -> Color {
+        let p = self.placement.point(hit);
+        let phase = p.x + 2.0 * noise::turbulence(p, self.octaves);
+        mix(
+            self.colors,
+            0.5 + 0.5 * (std::f32::consts::TAU * phase).sin(),
+        )
+    }
+}
+
+/// Growth rings around the z axis, slightly warped by noise.
+pub struct Wood {
+    pub colors: [Color; 2],
+    pub octaves: u32,
+    pub placement: Placement,
+}
+
+impl Texture for Wood {
+    fn value(&self, hit: &Intersection) -> Color {
+        let p = self.placement.point(hit);
+        let r = (p.x * p.x + p.y * p.y).sqrt() + 0.3 * noise::fbm(p, self.octaves);
+        // Light early wood darkening towards the end of each ring.
+        mix(self.colors, r.rem_euclid(1.0).powi(2))
+    }
+}
+
+fn mix(colors: [Color; 2], t: f32) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    colors[0] * (1.0 - t) + colors[1] * t
+}
+
+/// What happens to texture coordinates outside of [0, 1].
+#[derive(Copy, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Wrap {
+    Repeat,
+    Clamp,
+    Mirror,
+}
+
+impl Wrap {
+    fn apply(self, i: i64, size: usize) -> usize {
+        let n = size as i64;
+        let res = match self {
+            Wrap::Repeat => i.rem_euclid(n),
+            Wrap::Clamp => i.clamp(0, n - 1),
+            Wrap::Mirror => {
+                let m = i.rem_euclid(2 * n);
+                if m < n {
+                    m
+                } else {
+                    2 * n - 1 - m
+                }
+            }
+        };
+        res as usize
+    }
+}
+
+/// Bilinearly filtered image, v = 0 being its bottom row.
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    // Linear values, top row first.
+    pixels: Vec<Color>,
+    wrap: Wrap,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Color>, wrap: Wrap) -> ImageTexture {
+        assert_eq!(width * height, pixels.len());
+        ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap,
+        }
+    }
+
+    /// Loads an image file. Floating point formats like OpenEXR and Radiance
+    /// HDR are taken to be linear already, anything else is decoded from sRGB.
+    pub fn load(path: &Path, wrap: Wrap) -> Result<ImageTexture, ImageError> {
+        ImageTexture::open(path, wrap, true)
+    }
+
+    /// Loads an image holding data rather than colors, like a normal map,
+    /// whose values are used as they are.
+    pub fn load_linear(path: &Path, wrap: Wrap) -> Result<ImageTexture, ImageError> {
+        ImageTexture::open(path, wrap, false)
+    }
+
+    fn open(path: &Path, wrap: Wrap, srgb: bool) -> Result<ImageTexture, ImageError> {
+        let is_hdr = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("hdr"));
+        if is_hdr {
+            return ImageTexture::open_hdr(path, wrap);
+        }
+        let img = image::open(path)?;
+        let linear = !srgb || matches!(img.color(), ColorType::Rgb32F | ColorType::Rgba32F);
+        let img = img.into_rgb32f();
+        let decode = |v: f32| if linear { v } else { decode_srgb(v) };
+        let pixels = img
+            .pixels()
+            .map(|p| Color {
+                red: decode(p[0]),
+                green: decode(p[1]),
+                blue: decode(p[2]),
+            })
+            .collect();
+        Ok(ImageTexture::new(
+            img.width() as usize,
+            img.height() as usize,
+            pixels,
+            wrap,
+        ))
+    }
+
+    /// Radiance HDR files are read separately, as `image::open` would
+    /// convert them to 8 bits.
+    fn open_hdr(path: &Path, wrap: Wrap) -> Result<ImageTexture, ImageError> {
+        let file = File::open(path).map_err(ImageError::IoError)?;
+        let decoder = HdrDecoder::new(BufReader::new(file))?;
+        let meta = decoder.metadata();
+        let pixels = decoder
+            .read_image_hdr()?
+            .iter()
+            .map(|p| Color {
+                red: p[0],
+                green: p[1],
+                blue: p[2],
+            })
+            .collect();
+        Ok(ImageTexture::new(
+            meta.width as usize,
+            meta.height as usize,
+            pixels,
+            wrap,
+        ))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Unfiltered value, row 0 being the top.
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[self.width * y + x]
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Color {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        self.pixels[self.width * y + x]
+    }
+
+    pub fn lookup(&self, uv: [f32; 2]) -> Color {
+        // Texel centers sit at half integer coordinates.
+        let x = uv[0] * self.width as f32 - 0.5;
+        let y = (1.0 - uv[1]) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let top = self.texel(x0, y0) * (1.0 - tx) + self.texel(x0 + 1, y0) * tx;
+        let bottom = self.texel(x0, y0 + 1) * (1.0 - tx) + self.texel(x0 + 1, y0 + 1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, hit: &Intersection) -> Color {
+        self.lookup(hit.uv)
+    }
+}
+
+/// Inverse of the sRGB transfer function.
+fn decode_srgb(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(v: f32) -> Color {
+        Color {
+            red: v,
+            green: v,
+            blue: v,
+        }
+    }
+
+    #[test]
+    fn bilinear_lookup() {
+        // Black and white columns.
+        let texture = ImageTexture::new(2, 1, vec![gray(0.0), gray(1.0)], Wrap::Clamp);
+        assert_eq!(0.0, texture.lookup([0.25, 0.5]).red);
+        assert_eq!(1.0, texture.lookup([0.75, 0.5]).red);
+        assert_eq!(0.5, texture.lookup([0.5, 0.5]).red);
+        // Clamped beyond the edges.
+        assert_eq!(1.0, texture.lookup([1.5, 0.5]).red);
+    }
+
+    #[test]
+    fn wrap_modes() {
+        assert_eq!(1, Wrap::Repeat.apply(-3, 4));
+        assert_eq!(0, Wrap::Clamp.apply(-3, 4));
+        assert_eq!(3, Wrap::Clamp.apply(7, 4));
+        assert_eq!(2, Wrap::Mirror.apply(-3, 4));
+        assert_eq!(3, Wrap::Mirror.apply(4, 4));
+        assert_eq!(0, Wrap::Mirror.apply(7, 4));
+    }
+
+    #[test]
+    fn repeat_tiles() {
+        let texture = ImageTexture::new(2, 1, vec![gray(0.0), gray(1.0)], Wrap::Repeat);
+        assert_eq!(
+            texture.lookup([0.75, 0.5]).red,
+            texture.lookup([2.75, 0.5]).red
+        );
+        // Blends across the seam.
+        assert_eq!(0.5, texture.lookup([0.0, 0.5]).red);
+    }
+
+    fn hit_at(x: f32, y: f32, z: f32) -> Intersection {
+        let p = Vector { x, y, z };
+        Intersection {
+            point: Point { x, y, z },
+            normal: p,
+            shading_normal: p,
+            distance: 1.0,
+            inside: false,
+            uv: [0.0, 0.0],
+            dpdu: p,
+            dpdv: p,
+            local: Vector { x: x - 10.0, y, z },
+        }
+    }
+
+    #[test]
+    fn checker_alternates() {
+        let checker = Checker {
+            colors: [gray(0.0), gray(1.0)],
+            placement: Placement {
+                space: Space::World,
+                scale: 0.5,
+            },
+        };
+        assert_eq!(0.0, checker.value(&hit_at(0.2, 0.2, 0.0)).red);
+        assert_eq!(1.0, checker.value(&hit_at(0.7, 0.2, 0.0)).red);
+        assert_eq!(0.0, checker.value(&hit_at(0.7, 0.7, 0.0)).red);
+        // Rounding below a boundary stays in the cell above it.
+        assert_eq!(0.0, checker.value(&hit_at(0.2, 0.2, -1e-6)).red);
+        assert_eq!(1.0, checker.value(&hit_at(-0.2, 0.2, 0.0)).red);
+    }
+
+    #[test]
+    fn object_space() {
+        let placement = |space| Placement { space, scale: 3.0 };
+        let world = Checker {
+            colors: [gray(0.0), gray(1.0)],
+            placement: placement(Space::World),
+        };
+        let object = Checker {
+            colors: [gray(0.0), gray(1.0)],
+            placement: placement(Space::Object),
+        };
+        // The local point is 10 units off, moving 3 cells and a third.
+        let hit = hit_at(1.0, 1.0, 1.0);
+        assert_eq!(0.0, world.value(&hit).red);
+        assert_eq!(1.0, object.value(&hit).red);
+    }
+
+    #[test]
+    fn patterns_in_range() {
+        let colors = [gray(0.2), gray(0.6)];
+        let placement = Placement {
+            space: Space::World,
+            scale: 0.7,
+        };
+        let textures: Vec<Box<dyn Texture>> = vec![
+            Box::new(Noise {
+                colors,
+                octaves: 4,
+                turbulence: false,
+                placement,
+            }),
+            Box::new(Noise {
+                colors,
+                octaves: 4,
+                turbulence: true,
+                placement,
+            }),
+            Box::new(Marble {
+                colors,
+                octaves: 4,
+                placement,
+            }),
+            Box::new(Wood {
+                colors,
+                octaves: 4,
+                placement,
+            }),
+        ];
+        for texture in textures.iter() {
+            let mut values = Vec::new();
+            for i in 0..100 {
+                let v = texture.value(&hit_at(i as f32 * 0.37, 1.3, i as f32 * -0.11));
+                assert!((0.2..=0.6).contains(&v.red), "{}", v.red);
+                values.push(v.red);
+            }
+            // Not a constant.
+            assert!(values.iter().any(|&v| (v - values[0]).abs() > 0.05));
+        }
+    }
+
+    #[test]
+    fn srgb_decoding() {
+        assert_eq!(0.0, decode_srgb(0.0));
+        assert!((decode_srgb(1.0) - 1.0).abs() < 1e-6);
+        assert!((decode_srgb(0.5) - 0.214).abs() < 1e-3);
+    }
+}